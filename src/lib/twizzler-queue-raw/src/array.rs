@@ -0,0 +1,145 @@
+//! A queue that owns its buffer, for bounded channels within a single address space.
+
+use core::{cell::UnsafeCell, mem::MaybeUninit, sync::atomic::AtomicBool};
+
+use crate::{
+    ConsumerGuard, Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, RawQueueHdr,
+    ReceiveFlags, SubmissionFlags, MAX_L2LEN,
+};
+
+/// A raw queue with a fixed capacity of N entries, holding both its header and its buffer, so
+/// that there's no separate buffer to set up and keep in sync with the header. N must be a power
+/// of two, no larger than 2^30, which is checked at compile time.
+///
+/// This is handy for static or stack-allocated queues. Like [RawQueue], any number of threads may
+/// submit, but only one may receive. When the queue is dropped, any entries still in it are
+/// dropped too. A slot that a submitter reserved but never published can't be dropped, so it's
+/// leaked, along with any entries behind it.
+pub struct ArrayRawQueue<T: EntryData, const N: usize> {
+    hdr: RawQueueHdr,
+    // Slots are only read once they've been written and published, so the data doesn't need to be
+    // initialized, but the cmd_slots must start out zeroed.
+    buf: UnsafeCell<MaybeUninit<[QueueEntry<T>; N]>>,
+    receiving: AtomicBool,
+}
+
+impl<T: EntryData, const N: usize> ArrayRawQueue<T, N> {
+    const VALID_LEN: () = assert!(
+        N.is_power_of_two() && N <= 1 << MAX_L2LEN,
+        "queue length must be a power of two, no larger than 2^30"
+    );
+
+    /// Construct a new, empty queue.
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_LEN;
+        Self {
+            hdr: RawQueueHdr::new(
+                N.trailing_zeros() as usize,
+                core::mem::size_of::<QueueEntry<T>>(),
+            ),
+            buf: UnsafeCell::new(MaybeUninit::zeroed()),
+            receiving: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the queue's header, for the operations that work on it directly, like
+    /// [RawQueueHdr::stats] and [RawQueueHdr::close].
+    pub fn hdr(&self) -> &RawQueueHdr {
+        &self.hdr
+    }
+
+    // A view of the queue for a single operation. Each view has its own receiving flag, so
+    // receives check ours instead.
+    fn queue(&self) -> RawQueue<T> {
+        unsafe { RawQueue::new(&self.hdr, self.buf.get().cast()) }
+    }
+
+    /// Submit an entry to the queue. The callbacks and flags work as in [RawQueue::submit].
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.queue().submit(item, wait, ring, flags)
+    }
+
+    /// Receive an entry from the queue. The callbacks and flags work as in [RawQueue::receive].
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        let _guard = ConsumerGuard::enter(&self.receiving)?;
+        self.queue().receive(wait, ring, flags)
+    }
+}
+
+impl<T: EntryData, const N: usize> Default for ArrayRawQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: EntryData, const N: usize> Drop for ArrayRawQueue<T, N> {
+    fn drop(&mut self) {
+        self.queue().drain(|_| {}).for_each(drop);
+    }
+}
+
+unsafe impl<T: EntryData + Send, const N: usize> Send for ArrayRawQueue<T, N> {}
+unsafe impl<T: EntryData + Send, const N: usize> Sync for ArrayRawQueue<T, N> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{
+        spin_wait as wait, spin_wake as wake, ArrayRawQueue, QueueEntry, ReceiveFlags,
+        SubmissionFlags,
+    };
+
+    static QUEUE: ArrayRawQueue<u64, 4> = ArrayRawQueue::new();
+
+    #[test]
+    fn it_transmits_through_arrays() {
+        for i in 0..4 {
+            let res = QUEUE.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(QUEUE.hdr().stats().occupancy, 4);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 4..100 {
+                    let res =
+                        QUEUE.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            for i in 0..100 {
+                let res = QUEUE.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+        assert_eq!(QUEUE.hdr().capacity(), 4);
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    #[test]
+    fn it_drops_leftovers() {
+        use crate::test_util::Drops;
+
+        let drops = Drops::default();
+        let q = ArrayRawQueue::<_, 4>::new();
+        for i in 0..3 {
+            let item = QueueEntry::new(i, drops.payload());
+            assert!(q.submit(item, wait, wake, SubmissionFlags::empty()).is_ok());
+        }
+        drop(q.receive(wait, wake, ReceiveFlags::empty()));
+        assert_eq!(drops.count(), 1);
+        drop(q);
+        assert_eq!(drops.count(), 3);
+    }
+}
@@ -0,0 +1,442 @@
+//! Async support for raw queues, where the consumer (and optionally the producers) are async tasks
+//! instead of threads.
+
+use core::{
+    cell::Cell,
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use std::{sync::Mutex, vec::Vec};
+
+use futures::{task::AtomicWaker, Stream};
+
+use crate::{
+    Counter, CounterValue, EntryData, QueueEntry, QueueError, QueueWaiter, RawQueue, ReceiveFlags,
+    SubmissionFlags,
+};
+
+/// A waiter that bridges the queue's wait and ring callbacks to async tasks. Producers may still
+/// block synchronously when the queue is full, using the `wait` and `ring` callbacks this is
+/// constructed with (for example, a futex wait and wake). But when a producer rings the doorbell,
+/// the consuming task's waker is woken as well, so that the consumer can suspend instead of
+/// blocking its thread.
+///
+/// The waiter keeps a waker for each word that tasks wait on: the consuming task's waker, for the
+/// bell, and a set of wakers for async producers waiting for room, for the tail.
+pub struct AsyncWaiter<W, R> {
+    waker: AtomicWaker,
+    submitters: Mutex<Vec<Waker>>,
+    wait: W,
+    ring: R,
+}
+
+impl<W: Fn(&Counter, CounterValue), R: Fn(&Counter)> AsyncWaiter<W, R> {
+    /// Construct a new waiter out of the synchronous wait and ring callbacks, which work as in
+    /// [RawQueue::submit].
+    pub fn new(wait: W, ring: R) -> Self {
+        Self {
+            waker: AtomicWaker::new(),
+            submitters: Mutex::new(Vec::new()),
+            wait,
+            ring,
+        }
+    }
+
+    /// Register the waker of the consuming task, to be woken on the next ring.
+    pub fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    /// The wait callback, for producers that need to block. Waits until *word != val.
+    pub fn wait(&self, word: &Counter, val: CounterValue) {
+        (self.wait)(word, val)
+    }
+
+    /// Register the waker of a producer task that's waiting for room in the queue, to be woken
+    /// the next time the consumer frees up a slot.
+    pub fn register_submitter(&self, waker: &Waker) {
+        let mut submitters = self.submitters.lock().unwrap();
+        if !submitters.iter().any(|w| w.will_wake(waker)) {
+            submitters.push(waker.clone());
+        }
+    }
+
+    /// The ring callback, which wakes anyone blocked on the word, and wakes the consuming task.
+    pub fn ring(&self, word: &Counter) {
+        (self.ring)(word);
+        self.waker.wake();
+    }
+
+    /// The ring callback for the consumer, which wakes anyone blocked on the word, and wakes all
+    /// the producer tasks waiting for room.
+    pub fn ring_submitters(&self, word: &Counter) {
+        (self.ring)(word);
+        let submitters = core::mem::take(&mut *self.submitters.lock().unwrap());
+        for waker in submitters {
+            waker.wake();
+        }
+    }
+}
+
+impl<W: Fn(&Counter, CounterValue), R: Fn(&Counter)> QueueWaiter for AsyncWaiter<W, R> {
+    fn wait(&self, word: &Counter, expected: CounterValue) {
+        AsyncWaiter::wait(self, word, expected)
+    }
+
+    fn wake(&self, word: &Counter) {
+        self.ring(word)
+    }
+}
+
+/// A raw queue with an async consumer. Any number of producers may submit to the queue, but only
+/// a single task may receive from it.
+pub struct AsyncQueue<T, W, R> {
+    queue: RawQueue<T>,
+    waiter: AsyncWaiter<W, R>,
+}
+
+impl<T: Copy + EntryData, W: Fn(&Counter, CounterValue), R: Fn(&Counter)> AsyncQueue<T, W, R> {
+    /// Construct a new async queue over a raw queue, using a waiter to bridge wakes to the
+    /// consuming task.
+    pub fn new(queue: RawQueue<T>, waiter: AsyncWaiter<W, R>) -> Self {
+        Self { queue, waiter }
+    }
+
+    /// Get the underlying raw queue.
+    pub fn queue(&self) -> &RawQueue<T> {
+        &self.queue
+    }
+
+    /// Close the queue, waking the consuming task and any producer tasks waiting for room, which
+    /// then complete with Err([QueueError::Closed]). See [crate::RawQueueHdr::close].
+    pub fn close(&self) {
+        self.queue.hdr().close(|w| {
+            self.waiter.ring(w);
+            self.waiter.ring_submitters(w);
+        });
+    }
+
+    /// Submit an entry to the queue, waking the consuming task. If the queue is full, this blocks
+    /// synchronously (unless NON_BLOCK is specified) using the waiter's wait callback.
+    pub fn submit(&self, item: QueueEntry<T>, flags: SubmissionFlags) -> Result<(), QueueError> {
+        self.queue.submit(
+            item,
+            |w, v| self.waiter.wait(w, v),
+            |w| self.waiter.ring(w),
+            flags,
+        )
+    }
+
+    /// Submit an entry to the queue from an async task, waking the consuming task. If the queue
+    /// is full, the task is suspended until the consumer makes room (unless NON_BLOCK is
+    /// specified, in which case this completes with Err([QueueError::WouldBlock])).
+    ///
+    /// Wakeups may be spurious (all waiting producers are woken when a slot frees up, and only
+    /// one may get it), so each poll retries the submission, and goes back to waiting if the
+    /// queue is still full. A slot is only claimed once there's room for it, so dropping the
+    /// future while it's waiting leaves the queue as it was.
+    pub fn submit_async(
+        &self,
+        item: QueueEntry<T>,
+        flags: SubmissionFlags,
+    ) -> SubmitFuture<'_, T, W, R> {
+        SubmitFuture {
+            queue: self,
+            item,
+            flags,
+            waiting: Cell::new(false),
+        }
+    }
+
+    /// Poll for an entry from the queue. If none are ready, this registers the task's waker to be
+    /// woken when the next entry is submitted, and returns Pending (unless NON_BLOCK is
+    /// specified, in which case this returns Err([QueueError::WouldBlock])).
+    pub fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        flags: ReceiveFlags,
+    ) -> Poll<Result<QueueEntry<T>, QueueError>> {
+        let sleep = !flags.contains(ReceiveFlags::NON_BLOCK);
+        // Register before announcing that we're waiting, so a producer that sees us waiting is
+        // guaranteed to wake this task.
+        if sleep {
+            self.waiter.register(cx.waker());
+        }
+        let mut output = None;
+        let mut waiter = (None, 0);
+        let mut ringer = None;
+        match self
+            .queue
+            .setup_sleep(sleep, &mut output, &mut waiter, &mut ringer)
+        {
+            Ok(()) => {
+                // Only producers waiting for room need waking here, not this task.
+                if let Some(ringer) = ringer {
+                    self.waiter.ring_submitters(ringer);
+                }
+                Poll::Ready(Ok(output.unwrap()))
+            }
+            Err(QueueError::WouldBlock) if sleep => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Receive an entry from the queue, suspending the task until one is available (unless
+    /// NON_BLOCK is specified). Wakeups may be spurious, in which case the queue is checked again
+    /// and the task goes back to waiting.
+    pub async fn receive_async(&self, flags: ReceiveFlags) -> Result<QueueEntry<T>, QueueError> {
+        poll_fn(|cx| self.poll_recv(cx, flags)).await
+    }
+
+    /// Receive an entry from the queue, suspending the task until one is available.
+    pub async fn recv(&self) -> Result<QueueEntry<T>, QueueError> {
+        self.receive_async(ReceiveFlags::empty()).await
+    }
+
+    /// Turn the queue into a stream of the entries received from it. The stream ends once the
+    /// queue is closed and drained. See [RecvStream].
+    pub fn into_stream(self) -> RecvStream<T, W, R> {
+        RecvStream { queue: self }
+    }
+}
+
+impl<T: Copy + EntryData> RawQueue<T> {
+    /// Turn the queue into a stream of the entries received from it, using a waiter to bridge
+    /// wakes to the consuming task. See [AsyncQueue::into_stream].
+    pub fn into_stream<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        self,
+        waiter: AsyncWaiter<W, R>,
+    ) -> RecvStream<T, W, R> {
+        AsyncQueue::new(self, waiter).into_stream()
+    }
+}
+
+/// A stream of the entries received from a queue. See [AsyncQueue::into_stream].
+///
+/// Each poll tries to receive an entry, and if none are ready, registers the task's waker to be
+/// woken by the next submission. The stream ends once the queue is closed and drained. Errors
+/// other than closing (such as [QueueError::Reentrant]) can't be yielded as items, so they also
+/// end the stream; use [AsyncQueue::poll_recv] to see them.
+pub struct RecvStream<T, W, R> {
+    queue: AsyncQueue<T, W, R>,
+}
+
+impl<T, W, R> RecvStream<T, W, R> {
+    /// Get the queue the stream receives from, for example to submit to it.
+    pub fn get_ref(&self) -> &AsyncQueue<T, W, R> {
+        &self.queue
+    }
+
+    /// Get back the queue the stream receives from.
+    pub fn into_inner(self) -> AsyncQueue<T, W, R> {
+        self.queue
+    }
+}
+
+impl<T: Copy + EntryData, W: Fn(&Counter, CounterValue), R: Fn(&Counter)> Stream
+    for RecvStream<T, W, R>
+{
+    type Item = QueueEntry<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.queue
+            .poll_recv(cx, ReceiveFlags::empty())
+            .map(Result::ok)
+    }
+}
+
+/// A future for an async submission. See [AsyncQueue::submit_async].
+pub struct SubmitFuture<'a, T, W, R> {
+    queue: &'a AsyncQueue<T, W, R>,
+    item: QueueEntry<T>,
+    flags: SubmissionFlags,
+    // Whether we've counted ourselves as a waiting submitter, so that the consumer rings.
+    waiting: Cell<bool>,
+}
+
+impl<'a, T, W, R> SubmitFuture<'a, T, W, R> {
+    fn set_waiting(&self, waiting: bool) {
+        if self.waiting.replace(waiting) != waiting {
+            let hdr = self.queue.queue.hdr();
+            if waiting {
+                hdr.inc_submit_waiting();
+            } else {
+                hdr.dec_submit_waiting();
+            }
+        }
+    }
+}
+
+impl<'a, T: Copy + EntryData, W: Fn(&Counter, CounterValue), R: Fn(&Counter)> Future
+    for SubmitFuture<'a, T, W, R>
+{
+    type Output = Result<(), QueueError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let queue = self.queue;
+        let submit = || queue.queue.try_submit(self.item, |w| queue.waiter.ring(w));
+        let res = match submit() {
+            Err(QueueError::WouldBlock) if !self.flags.contains(SubmissionFlags::NON_BLOCK) => {
+                // Register, and only then check again, so that a slot freed up in between isn't
+                // missed.
+                queue.waiter.register_submitter(cx.waker());
+                self.set_waiting(true);
+                match submit() {
+                    Err(QueueError::WouldBlock) => return Poll::Pending,
+                    res => res,
+                }
+            }
+            res => res,
+        };
+        self.set_waiting(false);
+        Poll::Ready(res)
+    }
+}
+
+impl<'a, T, W, R> Drop for SubmitFuture<'a, T, W, R> {
+    fn drop(&mut self) {
+        self.set_waiting(false);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use futures::Stream;
+
+    use super::{AsyncQueue, AsyncWaiter};
+    use crate::{
+        spin_wait as wait, spin_wake as wake, QueueEntry, QueueError, RawQueue, RawQueueHdr,
+        ReceiveFlags, SubmissionFlags,
+    };
+
+    struct CountingWaker(AtomicU32);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn it_wakes_async_consumer() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let aq = AsyncQueue::new(q, AsyncWaiter::new(wait, wake));
+
+        let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(aq.poll_recv(&mut cx, ReceiveFlags::empty()).is_pending());
+        assert_eq!(
+            aq.submit(QueueEntry::new(1, 7), SubmissionFlags::empty()),
+            Ok(())
+        );
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        match aq.poll_recv(&mut cx, ReceiveFlags::empty()) {
+            Poll::Ready(Ok(e)) => assert_eq!(e.info(), 1),
+            _ => panic!("expected an entry"),
+        }
+
+        // With the consumer no longer waiting, producers don't need to wake it.
+        assert_eq!(
+            aq.submit(QueueEntry::new(2, 7), SubmissionFlags::empty()),
+            Ok(())
+        );
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(aq.poll_recv(&mut cx, ReceiveFlags::empty()).is_ready());
+        assert!(matches!(
+            aq.poll_recv(&mut cx, ReceiveFlags::NON_BLOCK),
+            Poll::Ready(Err(QueueError::WouldBlock))
+        ));
+    }
+
+    #[test]
+    fn it_wakes_async_submitters() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let aq = AsyncQueue::new(q, AsyncWaiter::new(wait, wake));
+
+        let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        for i in 0..4 {
+            let mut fut = pin!(aq.submit_async(QueueEntry::new(i, 7), SubmissionFlags::empty()));
+            assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        }
+        let mut fut = pin!(aq.submit_async(QueueEntry::new(4, 7), SubmissionFlags::empty()));
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert!(qh.submitter_waiting());
+        // A dropped submission must not stay counted as waiting.
+        let mut dropped =
+            Box::pin(aq.submit_async(QueueEntry::new(5, 7), SubmissionFlags::empty()));
+        assert!(dropped.as_mut().poll(&mut cx).is_pending());
+        drop(dropped);
+
+        let mut nb = pin!(aq.submit_async(QueueEntry::new(6, 7), SubmissionFlags::NON_BLOCK));
+        assert_eq!(
+            nb.as_mut().poll(&mut cx),
+            Poll::Ready(Err(QueueError::WouldBlock))
+        );
+
+        assert!(aq.poll_recv(&mut cx, ReceiveFlags::empty()).is_ready());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        assert!(!qh.submitter_waiting());
+        for i in 1..5 {
+            match aq.poll_recv(&mut cx, ReceiveFlags::empty()) {
+                Poll::Ready(Ok(e)) => assert_eq!(e.info(), i),
+                _ => panic!("expected an entry"),
+            }
+        }
+    }
+
+    #[test]
+    fn it_streams_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let mut stream = pin!(q.into_stream(AsyncWaiter::new(wait, wake)));
+
+        let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(stream.as_mut().poll_next(&mut cx).is_pending());
+        for i in 0..2 {
+            assert_eq!(
+                stream
+                    .get_ref()
+                    .submit(QueueEntry::new(i, 7), SubmissionFlags::empty()),
+                Ok(())
+            );
+        }
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        stream.get_ref().close();
+        // Entries submitted before closing are still yielded, and then the stream ends.
+        for i in 0..2 {
+            match stream.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(e)) => assert_eq!(e.info(), i),
+                _ => panic!("expected an entry"),
+            }
+        }
+        assert_eq!(
+            stream.as_mut().poll_next(&mut cx).map(|e| e.is_some()),
+            Poll::Ready(false)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! A queue where every consumer receives every entry, built on the ring of a raw queue.
+
+use core::sync::atomic::Ordering;
+
+use crate::{
+    Backoff, Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, ReceiveFlags,
+    SubmissionFlags, CONSUMER_WAITING, COUNTER_MASK,
+};
+
+/// A queue for fanning entries out to N consumers, each of which receives a copy of every entry.
+/// Any number of threads may submit, and each consumer receives with its own id, from 0 to N - 1.
+/// Each id must only be used by one thread at a time.
+///
+/// Each consumer has its own tail, kept in this struct, and receives by copying the entry at its
+/// tail out of the buffer and moving its tail on, without consuming the entry for anyone else.
+/// The tail in the queue's header is kept at the slowest consumer's tail: after a consumer moves
+/// its own tail, it recomputes the minimum of all the tails and moves the header's tail up to it.
+/// Since submitters reserve and wait for room against the header's tail, a slot is only reused once
+/// every consumer has passed it, and a consumer that falls behind blocks the producers.
+///
+/// The consumers all wait on the queue's bell, so the ring callback that producers use must wake
+/// all the threads waiting on it, not just one. As with [RawQueue::receive_mpmc], the consumer's
+/// waiting flag is left set once any consumer has waited. The queue's own receive functions must
+/// not be used while it's a broadcast queue.
+pub struct BroadcastRawQueue<T, const N: usize> {
+    queue: RawQueue<T>,
+    tails: [Counter; N],
+}
+
+impl<T: Copy + EntryData, const N: usize> BroadcastRawQueue<T, N> {
+    const VALID_CONSUMERS: () = assert!(N > 0, "a broadcast queue needs at least one consumer");
+
+    /// Construct a new broadcast queue out of a queue. Every consumer starts out at the queue's
+    /// current tail, so entries already in the queue are received by all of them.
+    pub fn new(queue: RawQueue<T>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_CONSUMERS;
+        let tail = queue.hdr().tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        Self {
+            queue,
+            tails: core::array::from_fn(|_| Counter::new(tail)),
+        }
+    }
+
+    /// Submit a data item to every consumer. The callbacks and flags work as in
+    /// [RawQueue::submit], except that the queue is full when the slowest consumer is a whole
+    /// queue's worth of entries behind.
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.queue.submit(item, wait, ring, flags)
+    }
+
+    /// Receive the next entry for the given consumer, which must be less than N (otherwise this
+    /// returns Err([QueueError::Unknown])). The callbacks and flags work as in [RawQueue::receive].
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        consumer: usize,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        let tail = self.tails.get(consumer).ok_or(QueueError::Unknown)?;
+        loop {
+            let t = self.next_ready(tail, &wait, flags)?;
+            let item = unsafe { QueueEntry::read_from(self.queue.get_buf(t as usize)) };
+            self.queue.check_entry(t, &item)?;
+            tail.store(t.wrapping_add(1) & COUNTER_MASK, Ordering::SeqCst);
+            self.advance_min(&ring);
+            if !item.is_abandoned() {
+                return Ok(item);
+            }
+        }
+    }
+
+    // Wait until the entry at the consumer's tail is ready, returning its position.
+    fn next_ready<W: Fn(&Counter, CounterValue)>(
+        &self,
+        tail: &Counter,
+        wait: W,
+        flags: ReceiveFlags,
+    ) -> Result<CounterValue, QueueError> {
+        let hdr = self.queue.hdr();
+        let t = tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        let item = self.queue.get_buf(t as usize) as *const QueueEntry<T>;
+        let mut backoff = hdr.backoff();
+        let mut waiting = false;
+        loop {
+            let b = hdr.bell.load(Ordering::SeqCst);
+            if !hdr.is_empty(b, t) && hdr.is_turn(t, item) {
+                return Ok(t);
+            }
+
+            if hdr.is_closed() {
+                return Err(QueueError::Closed);
+            }
+
+            if flags.contains(ReceiveFlags::NON_BLOCK) {
+                return Err(QueueError::WouldBlock);
+            }
+
+            if !waiting && backoff.spin() {
+                continue;
+            }
+
+            waiting = true;
+            hdr.consumer_set_waiting(true);
+            let b = hdr.bell.load(Ordering::SeqCst);
+            if hdr.is_empty(b, t) || !hdr.is_turn(t, item) {
+                hdr.wait_bell(&wait, b);
+            }
+        }
+    }
+
+    // Move the header's tail up to the slowest consumer's tail, and wake any submitters waiting
+    // for room. Every consumer's tail is between the header's tail and a queue's length past it,
+    // so the slowest is the one closest to the header's tail.
+    fn advance_min<R: Fn(&Counter)>(&self, ring: R) {
+        let hdr = self.queue.hdr();
+        let mut raw = hdr.tail.load(Ordering::SeqCst);
+        loop {
+            let old = raw & COUNTER_MASK;
+            let min = self
+                .tails
+                .iter()
+                .map(|t| t.load(Ordering::SeqCst) & COUNTER_MASK)
+                .min_by_key(|t| t.wrapping_sub(old) & COUNTER_MASK)
+                .unwrap_or(old);
+            if min == old {
+                return;
+            }
+            // Keep the waiting flag, which other consumers may have set.
+            let next = min | (raw & CONSUMER_WAITING);
+            match hdr
+                .tail
+                .compare_exchange(raw, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(cur) => raw = cur,
+            }
+        }
+        if hdr.submitter_waiting() {
+            ring(&hdr.tail);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{
+        spin_wait as wait, spin_wake as wake, BroadcastRawQueue, Info, QueueEntry, QueueError,
+        RawQueue, RawQueueHdr, ReceiveFlags, SubmissionFlags,
+    };
+
+    #[test]
+    fn it_broadcasts() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q: BroadcastRawQueue<u32, 3> =
+            BroadcastRawQueue::new(unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) });
+
+        for i in 0..4 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        // The queue stays full until every consumer has received the first entry.
+        for c in 0..2 {
+            let res = q.receive(c, wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), 0);
+            assert_eq!(qh.stats().occupancy, 4);
+        }
+        let res = q.receive(2, wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+        assert_eq!(qh.stats().occupancy, 3);
+
+        let res = q.receive(3, wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap_err(), QueueError::Unknown);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 4..100 {
+                    let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            for c in 0..3 {
+                let q = &q;
+                s.spawn(move || {
+                    for i in 1..100 as Info {
+                        let res = q.receive(c, wait, wake, ReceiveFlags::empty());
+                        assert_eq!(res.unwrap().info(), i);
+                    }
+                    let res = q.receive(c, wait, wake, ReceiveFlags::NON_BLOCK);
+                    assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,121 @@
+//! A pair of queues for the request/response pattern: requests go out on a submission queue, and
+//! the responses come back on a completion queue, matched up by their info tags.
+
+use crate::{Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue};
+
+/// A submission queue and a completion queue, used together from the requesting side. This is the
+/// pattern that twizzler-queue builds on top of the raw queues, without the allocation and the
+/// async machinery, so that it's usable in the kernel.
+///
+/// The requesting side is the only consumer of the completion queue, and the responding side
+/// tags each completion with the info of the submission that it completes.
+pub struct RawDuplex<S, C> {
+    submission: RawQueue<S>,
+    completion: RawQueue<C>,
+}
+
+impl<S: EntryData, C: EntryData> RawDuplex<S, C> {
+    /// Construct a new duplex out of a submission queue and a completion queue.
+    pub fn new(submission: RawQueue<S>, completion: RawQueue<C>) -> Self {
+        Self {
+            submission,
+            completion,
+        }
+    }
+
+    /// Get the submission queue.
+    pub fn submission(&self) -> &RawQueue<S> {
+        &self.submission
+    }
+
+    /// Get the completion queue.
+    pub fn completion(&self) -> &RawQueue<C> {
+        &self.completion
+    }
+
+    /// Get back the submission and completion queues.
+    pub fn into_parts(self) -> (RawQueue<S>, RawQueue<C>) {
+        (self.submission, self.completion)
+    }
+
+    /// Submit a request, and wait for the completion with the same info tag. The callbacks work as
+    /// in [RawQueue::submit] and [RawQueue::receive], and are used for both queues. See
+    /// [RawQueue::submit_and_wait].
+    ///
+    /// Completions may arrive out of order, if more than one request is outstanding. Entries can
+    /// only be removed from the front of the queue, so every completion ahead of the matching one
+    /// is received as well, and handed to `other`, in the order they arrive, for the caller to
+    /// keep until it asks for them (for instance, in a table indexed by info). The caller must
+    /// check what it kept before waiting for a completion again, since a completion that has
+    /// already been handed to `other` won't be received a second time.
+    pub fn submit_and_wait_completion<W, R, F>(
+        &self,
+        item: QueueEntry<S>,
+        wait: W,
+        ring: R,
+        other: F,
+    ) -> Result<QueueEntry<C>, QueueError>
+    where
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+        F: FnMut(QueueEntry<C>),
+    {
+        self.submission
+            .submit_and_wait(item, &self.completion, wait, ring, other)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::RawDuplex;
+    use crate::{
+        spin_wait as wait, spin_wake as wake, QueueEntry, RawQueue, RawQueueHdr, ReceiveFlags,
+        SubmissionFlags,
+    };
+
+    #[test]
+    fn it_matches_completions() {
+        let sh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut sbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let ch = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u64>>());
+        let mut cbuf = [QueueEntry::<u64>::default(); 1 << 2];
+        let duplex = unsafe {
+            RawDuplex::new(
+                RawQueue::new(&sh, sbuf.as_mut_ptr()),
+                RawQueue::new(&ch, cbuf.as_mut_ptr()),
+            )
+        };
+        let responder = unsafe {
+            (
+                RawQueue::new(&sh, sbuf.as_mut_ptr()),
+                RawQueue::new(&ch, cbuf.as_mut_ptr()),
+            )
+        };
+
+        // An earlier request completes after the next one was submitted.
+        let res = responder
+            .1
+            .submit(QueueEntry::new(2, 20), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let req = responder.0.receive(wait, wake, ReceiveFlags::empty());
+                let req = req.unwrap();
+                let res = responder.1.submit(
+                    QueueEntry::new(req.info(), req.item() as u64 * 10),
+                    wait,
+                    wake,
+                    SubmissionFlags::empty(),
+                );
+                assert_eq!(res, Ok(()));
+            });
+            let mut kept = Vec::new();
+            let res = duplex.submit_and_wait_completion(QueueEntry::new(1, 1), wait, wake, |c| {
+                kept.push((c.info(), c.item()))
+            });
+            let res = res.unwrap();
+            assert_eq!((res.info(), res.item()), (1, 10));
+            assert_eq!(kept, [(2, 20)]);
+        });
+    }
+}
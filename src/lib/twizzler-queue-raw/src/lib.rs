@@ -13,7 +13,8 @@
 //!   2. A buffer, which contains the items that are enqueued.
 //!
 //! The queue is an MPSC lock-free blocking data structure. Any thread may submit to a queue, but
-//! only one thread may receive on that queue at a time. The queue is implemented with a head
+//! only one thread may receive on that queue at a time (unless all the consumers use
+//! [RawQueue::receive_mpmc]). The queue is implemented with a head
 //! pointer, a tail pointer, a doorbell, and a waiters counter. Additionally, the queue is
 //! maintained in terms of "turns", that indicate which "go around" of the queue we are on (mod 2).
 //!
@@ -73,40 +74,215 @@
 //! ```
 
 #![cfg_attr(test, feature(test))]
+#![feature(offset_of)]
 #![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 use core::{
-    cell::UnsafeCell,
     marker::PhantomData,
-    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    mem::{ManuallyDrop, MaybeUninit},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use twizzler_abi::marker::BaseType;
-#[derive(Clone, Copy, Default, Debug)]
+
+mod spsc;
+pub use spsc::{SpscConsumer, SpscProducer};
+
+// The array queue starts from a zeroed buffer, which isn't a valid loom atomic.
+#[cfg(not(loom))]
+mod array;
+#[cfg(not(loom))]
+pub use array::ArrayRawQueue;
+
+mod priority;
+pub use priority::{Priority, PriorityRawQueue};
+
+mod broadcast;
+pub use broadcast::BroadcastRawQueue;
+
+mod split;
+pub use split::{RawReceiver, RawSender};
+
+mod duplex;
+pub use duplex::RawDuplex;
+
+#[cfg(any(feature = "std", test))]
+mod std_waiter;
+#[cfg(any(feature = "std", test))]
+pub use std_waiter::StdWaiter;
+
+#[cfg(any(feature = "std", test))]
+mod owned;
+#[cfg(any(feature = "std", test))]
+pub use owned::{OwnedQueue, Receiver, Sender};
+
+// A drop counter isn't plain data, so it can't be checksummed.
+#[cfg(all(test, not(loom), not(feature = "checksum")))]
+mod test_util;
+
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::{OperationStats, SpinStats, SPIN_BUCKETS};
+
+// Pending submissions keep a copy of their entry, so async needs entries to be Copy, which they
+// aren't under loom.
+#[cfg(all(feature = "async", not(loom)))]
+mod async_queue;
+#[cfg(all(feature = "async", not(loom)))]
+pub use async_queue::{AsyncQueue, AsyncWaiter, RecvStream, SubmitFuture};
+
+// The atomic types that the queue is built from. These are the core atomics, but keeping every use
+// behind these aliases means that a test build can swap in a replacement here (a wrapper that logs
+// each access or injects delays, say) without touching the rest of the queue. A replacement needs
+// the same methods. Under loom, these are loom's atomics, so that the model-checked tests see every
+// access to the header and to each entry's cmd_slot (see CmdSlot).
+#[cfg(not(loom))]
+type QAtomicU32 = core::sync::atomic::AtomicU32;
+#[cfg(loom)]
+type QAtomicU32 = loom::sync::atomic::AtomicU32;
+#[cfg(all(
+    not(any(feature = "narrow-counters", not(target_has_atomic = "64"))),
+    not(loom)
+))]
+type QAtomicU64 = core::sync::atomic::AtomicU64;
+#[cfg(all(
+    not(any(feature = "narrow-counters", not(target_has_atomic = "64"))),
+    loom
+))]
+type QAtomicU64 = loom::sync::atomic::AtomicU64;
+
+#[cfg(not(any(feature = "narrow-counters", not(target_has_atomic = "64"))))]
+/// The atomic word used for the bell and tail counters, and thus the word of memory that the wait
+/// and ring callbacks operate on.
+pub type Counter = QAtomicU64;
+#[cfg(not(any(feature = "narrow-counters", not(target_has_atomic = "64"))))]
+/// The value held by a [Counter].
+pub type CounterValue = u64;
+
+#[cfg(any(feature = "narrow-counters", not(target_has_atomic = "64")))]
+/// The atomic word used for the bell and tail counters, and thus the word of memory that the wait
+/// and ring callbacks operate on. With the narrow-counters feature, this is a 32-bit word, which
+/// shrinks the header and avoids 64-bit atomics on targets that lack them. The counters only ever
+/// use 31 bits, so this doesn't shrink the counter space, but the bell wraps every 2^32
+/// submissions instead of effectively never.
+///
+/// Narrow counters are used automatically on targets without 64-bit atomics (such as 32-bit MIPS
+/// and PowerPC), whether or not the feature is enabled. Nothing else is lost on such targets, but
+/// since the header's layout changes, both sides of a shared queue must be built the same way. The
+/// queue still needs 32-bit read-modify-write atomics, so targets without any (such as thumbv6m)
+/// aren't supported.
+pub type Counter = QAtomicU32;
+#[cfg(any(feature = "narrow-counters", not(target_has_atomic = "64")))]
+/// The value held by a [Counter].
+pub type CounterValue = u32;
+
+#[cfg(not(feature = "wide-info"))]
+/// The info tag of a queue entry.
+pub type Info = u32;
+#[cfg(feature = "wide-info")]
+/// The info tag of a queue entry. With the wide-info feature, this is 64 bits, for callers that
+/// need more distinct tags than fit in 32 bits. This changes the layout of [QueueEntry], so a
+/// queue built with wide-info can't be shared with code built without it.
+pub type Info = u64;
+
+#[derive(Default, Debug)]
+#[cfg_attr(not(loom), derive(Clone, Copy))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 /// A queue entry. All queues must be formed of these, as the queue algorithm uses data inside this
 /// struct as part of its operation. The cmd_slot is used internally to track turn, and the info is
 /// used by the full queue structure to manage completion. The data T is user data passed around the
 /// queue.
+///
+/// With the `serde` feature, entries can be serialized, including the cmd_slot, so that a replayed
+/// entry keeps its turn bit. A deserialized entry is only meaningful if it's written back into a
+/// queue at the same index, and on the same turn, as the one it was taken from.
+///
+/// With the `checksum` feature, each entry also carries a CRC32 of its info tag and data, after
+/// the data. This changes the layout of entries, so both sides of a queue must agree on it. The
+/// data is hashed as raw bytes, so submitting and receiving need it to be free of padding (see
+/// [EntryData]).
+///
+/// With the `bytemuck` feature, entries are `Zeroable` and `AnyBitPattern` when their data is, so
+/// a region of shared memory can be viewed as a slice of entries with `bytemuck::cast_slice`.
+/// They aren't `Pod`, since depending on T and the enabled features there may be padding between
+/// or after the fields.
+///
+/// Entries are equal if their info tags and data are equal. The cmd_slot isn't compared, so an
+/// entry received from a queue is equal to the entry that was submitted.
 pub struct QueueEntry<T> {
-    cmd_slot: u32,
-    info: u32,
+    cmd_slot: CmdSlot,
+    info: Info,
     data: T,
+    #[cfg(feature = "checksum")]
+    crc: u32,
+}
+
+// The type of an entry's cmd_slot. It's a plain u32, accessed as an atomic in place, so that
+// entries can be Copy. Loom's atomics are separate objects that track their own history, so they
+// can't be overlaid on a plain field like that, and under loom the cmd_slot is a real atomic
+// instead (and entries aren't Copy).
+#[cfg(not(loom))]
+type CmdSlot = u32;
+#[cfg(loom)]
+type CmdSlot = QAtomicU32;
+
+#[inline]
+fn new_cmd_slot(v: u32) -> CmdSlot {
+    #[cfg(not(loom))]
+    return v;
+    #[cfg(loom)]
+    QAtomicU32::new(v)
 }
 
 impl<T> QueueEntry<T> {
+    #[inline]
+    fn cmd_slot_word(&self) -> &QAtomicU32 {
+        #[cfg(not(loom))]
+        return unsafe { core::mem::transmute::<&u32, &QAtomicU32>(&self.cmd_slot) };
+        #[cfg(loom)]
+        &self.cmd_slot
+    }
+
     #[inline]
     fn get_cmd_slot(&self) -> u32 {
-        unsafe { core::mem::transmute::<&u32, &AtomicU32>(&self.cmd_slot).load(Ordering::SeqCst) }
+        self.cmd_slot_word().load(ORD_OBSERVE)
     }
 
     #[inline]
     fn set_cmd_slot(&self, v: u32) {
-        unsafe {
-            core::mem::transmute::<&u32, &AtomicU32>(&self.cmd_slot).store(v, Ordering::SeqCst);
+        self.cmd_slot_word().store(v, ORD_PUBLISH);
+    }
+
+    // Move an entry into a slot, leaving the slot's cmd_slot alone, since the consumer may be
+    // watching it until the entry is published. The slot's old data has already been moved out
+    // (or was never written), so it isn't dropped.
+    #[inline]
+    unsafe fn write_into(self, slot: *mut Self) {
+        let item = ManuallyDrop::new(self);
+        core::ptr::addr_of_mut!((*slot).info).write(item.info);
+        core::ptr::addr_of_mut!((*slot).data).write(core::ptr::read(&item.data));
+    }
+
+    // Move an entry out of a slot, taking a snapshot of its cmd_slot. The data is left behind in
+    // the slot, so unless T is Copy, only one of the two may be dropped.
+    #[inline]
+    unsafe fn read_from(slot: *const Self) -> Self {
+        Self {
+            cmd_slot: new_cmd_slot((*slot).get_cmd_slot()),
+            info: core::ptr::addr_of!((*slot).info).read(),
+            data: core::ptr::addr_of!((*slot).data).read(),
+            #[cfg(feature = "checksum")]
+            crc: core::ptr::addr_of!((*slot).crc).read(),
         }
     }
 
+    #[inline]
+    fn is_abandoned(&self) -> bool {
+        self.get_cmd_slot() & CMD_SLOT_ABANDONED != 0
+    }
+
     #[inline]
     /// Get the data item of a QueueEntry.
     pub fn item(self) -> T {
@@ -115,21 +291,282 @@ impl<T> QueueEntry<T> {
 
     #[inline]
     /// Get the info tag of a QueueEntry.
-    pub fn info(&self) -> u32 {
+    pub fn info(&self) -> Info {
         self.info
     }
 
+    #[inline]
+    /// Get both the info tag and the data item of a QueueEntry.
+    pub fn info_and_item(self) -> (Info, T) {
+        (self.info, self.data)
+    }
+
+    /// Transform the data item of a QueueEntry, keeping the info tag and the internal cmd_slot
+    /// as they are.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> QueueEntry<U> {
+        QueueEntry {
+            cmd_slot: self.cmd_slot,
+            info: self.info,
+            data: f(self.data),
+            #[cfg(feature = "checksum")]
+            crc: 0,
+        }
+    }
+
     /// Construct a new QueueEntry. The `info` tag should be used to inform completion events in the
     /// full queue.
-    pub fn new(info: u32, item: T) -> Self {
+    pub fn new(info: Info, item: T) -> Self {
         Self {
-            cmd_slot: 0,
+            cmd_slot: new_cmd_slot(0),
             info,
             data: item,
+            #[cfg(feature = "checksum")]
+            crc: 0,
+        }
+    }
+
+    #[inline]
+    /// Get the low 30 bits of the position that a received QueueEntry was submitted at, to match
+    /// against a [SubmitTicket]. This is only meaningful for entries that have been published.
+    pub fn position(&self) -> u32 {
+        self.get_cmd_slot() & CMD_SLOT_INDEX_MASK
+    }
+
+    #[inline]
+    /// Get the producer id of a QueueEntry, as tagged by [QueueEntry::with_producer_id] or
+    /// [RawQueue::submit_as]. This is only meaningful if the producers on the queue agree to tag
+    /// their entries.
+    pub fn producer_id(&self) -> u8 {
+        (self.info >> PRODUCER_ID_SHIFT) as u8
+    }
+
+    #[inline]
+    /// Get the info tag of a QueueEntry with the producer id bits stripped off.
+    pub fn untagged_info(&self) -> Info {
+        self.info & PRODUCER_INFO_MASK
+    }
+
+    /// Tag a QueueEntry with a producer id. The id is carved from the high bits of the info tag,
+    /// so the info tag must fit within [PRODUCER_INFO_MASK].
+    pub fn with_producer_id(self, producer_id: u8) -> Self {
+        debug_assert_eq!(self.info & !PRODUCER_INFO_MASK, 0);
+        Self {
+            info: (self.info & PRODUCER_INFO_MASK) | ((producer_id as Info) << PRODUCER_ID_SHIFT),
+            ..self
+        }
+    }
+}
+
+// A bitwise CRC32 (the IEEE polynomial, as used by zlib), continuing from `crc`. Entries are
+// small, so a table isn't worth the space.
+#[cfg(feature = "checksum")]
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+// Publishing and checking entries needs their data to be hashable with the checksum feature, so
+// these are bounded on EntryData.
+impl<T: EntryData> QueueEntry<T> {
+    // Publish the entry to the consumer by storing its cmd_slot, once its info and data are
+    // written.
+    #[inline]
+    fn publish(&mut self, cmd_slot: u32) {
+        #[cfg(feature = "checksum")]
+        if cmd_slot & CMD_SLOT_ABANDONED == 0 {
+            self.crc = self.checksum();
+        }
+        self.set_cmd_slot(cmd_slot);
+    }
+
+    // The CRC32 of the info tag and the data, hashed as raw bytes. EntryData guarantees that the
+    // data has no padding, so every byte is initialized.
+    #[cfg(feature = "checksum")]
+    fn checksum(&self) -> u32 {
+        let info = self.info.to_ne_bytes();
+        !crc32(crc32(!0, &info), bytemuck::bytes_of(&self.data))
+    }
+
+    // Check that a published entry's info and data still match the checksum that the submitter
+    // stored, so that an entry scribbled over in the buffer isn't handed to the consumer.
+    #[cfg(feature = "checksum")]
+    #[inline]
+    fn verify(&self) -> Result<(), QueueError> {
+        if !self.is_abandoned() && self.crc != self.checksum() {
+            return Err(QueueError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "checksum"))]
+/// The data that queue entries can carry. Without the checksum feature, this is any type.
+pub trait EntryData {}
+#[cfg(not(feature = "checksum"))]
+impl<T> EntryData for T {}
+
+#[cfg(feature = "checksum")]
+/// The data that queue entries can carry. With the checksum feature, each entry's data is hashed
+/// as raw bytes, so it must not contain any padding or other uninitialized bytes, which
+/// bytemuck's `NoUninit` guarantees.
+pub trait EntryData: bytemuck::NoUninit {}
+#[cfg(feature = "checksum")]
+impl<T: bytemuck::NoUninit> EntryData for T {}
+
+// Entries compare by their info tag and data only. The cmd_slot records which slot and turn an
+// entry was submitted on (and the checksum follows from the rest), so an entry received from a
+// queue still equals the one that was submitted.
+impl<T: PartialEq> PartialEq for QueueEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.info == other.info && self.data == other.data
+    }
+}
+
+impl<T: Eq> Eq for QueueEntry<T> {}
+
+impl<T: core::hash::Hash> core::hash::Hash for QueueEntry<T> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.info.hash(state);
+        self.data.hash(state);
+    }
+}
+
+// Every field is plain data, and every bit pattern is a valid entry (if not necessarily a
+// published one), as long as it's a valid T.
+#[cfg(feature = "bytemuck")]
+unsafe impl<T: bytemuck::Zeroable> bytemuck::Zeroable for QueueEntry<T> {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl<T: bytemuck::AnyBitPattern> bytemuck::AnyBitPattern for QueueEntry<T> {}
+
+// The layout of entries is shared by everyone using a queue (userspace and the kernel, built
+// separately), so make sure it doesn't change by accident: the cmd_slot first, then the info tag,
+// then the data (and then the checksum).
+const _: () = {
+    use core::mem::{offset_of, size_of};
+    assert!(offset_of!(QueueEntry<u32>, cmd_slot) == 0);
+    assert!(offset_of!(QueueEntry<u32>, info) == size_of::<Info>());
+    assert!(offset_of!(QueueEntry<u32>, data) == 2 * size_of::<Info>());
+};
+
+#[cfg(not(feature = "wide-info"))]
+const _: () = assert!(core::mem::offset_of!(QueueEntry<u32>, data) == 8);
+
+#[cfg(feature = "checksum")]
+const _: () =
+    assert!(core::mem::offset_of!(QueueEntry<u32>, crc) == 2 * core::mem::size_of::<Info>() + 4);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg(feature = "bytemuck")]
+/// Possible errors from validating a [QueueEntry] read from untrusted bytes.
+pub enum ValidationError {
+    /// The byte buffer isn't the same size as the entry.
+    SizeMismatch,
+    /// The data bytes are not a valid bit pattern for the data type.
+    InvalidData,
+}
+
+#[cfg(feature = "bytemuck")]
+impl<T: bytemuck::CheckedBitPattern> QueueEntry<T> {
+    /// Reconstruct a QueueEntry from a byte buffer that came from an untrusted source, such as a
+    /// peer on the other side of an object boundary. Unlike reinterpreting the bytes directly, this
+    /// checks that the data bytes form a valid T (so, for example, an out-of-range enum
+    /// discriminant or a zero where T is a NonZero type is rejected) before returning the entry.
+    /// Any padding between the info tag and the data is ignored.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ValidationError> {
+        if bytes.len() != core::mem::size_of::<Self>() {
+            return Err(ValidationError::SizeMismatch);
         }
+        let info = core::mem::offset_of!(Self, info);
+        let start = core::mem::offset_of!(Self, data);
+        let data = bytemuck::checked::try_pod_read_unaligned::<T>(
+            &bytes[start..start + core::mem::size_of::<T>()],
+        )
+        .map_err(|_| ValidationError::InvalidData)?;
+        Ok(Self {
+            cmd_slot: new_cmd_slot(u32::from_ne_bytes(bytes[0..4].try_into().unwrap())),
+            info: Info::from_ne_bytes(
+                bytes[info..info + core::mem::size_of::<Info>()]
+                    .try_into()
+                    .unwrap(),
+            ),
+            data,
+            #[cfg(feature = "checksum")]
+            crc: {
+                let crc = core::mem::offset_of!(Self, crc);
+                u32::from_ne_bytes(bytes[crc..crc + 4].try_into().unwrap())
+            },
+        })
     }
 }
 
+// The head, bell, and tail count positions in the queue modulo 2^31, so every comparison between
+// them is done on the masked difference. The top bit of the tail word is the consumer's waiting
+// flag, which the mask also strips off.
+const COUNTER_MASK: CounterValue = 0x7fffffff;
+const CONSUMER_WAITING: CounterValue = 1 << 31;
+// Closing a queue flips this bit of the bell and the tail, which the mask also strips off, so
+// that the words waiters compare against change (see RawQueueHdr::close). On the tail, this is
+// the consumer's waiting flag.
+const CLOSE_TOGGLE: CounterValue = 1 << 31;
+// The same mask, for positions held in a u32.
+const POSITION_MASK: u32 = 0x7fffffff;
+
+// The cmd_slot of a published entry holds the turn bit, a flag marking entries that a producer
+// reserved but gave up on, and the low bits of the head position the entry was submitted at.
+const CMD_SLOT_TURN: u32 = 1 << 31;
+const CMD_SLOT_ABANDONED: u32 = 1 << 30;
+const CMD_SLOT_INDEX_MASK: u32 = CMD_SLOT_ABANDONED - 1;
+
+/// The largest supported l2len: a queue can hold at most 2^30 entries (see [max_capacity]). The
+/// head, bell, and tail count positions modulo 2^31, and the occupancy and turn arithmetic is only
+/// right if that covers at least two laps of the buffer.
+pub const MAX_L2LEN: usize = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Where a submission landed in the queue. See [RawQueue::submit_tracked].
+pub struct SubmitTicket {
+    /// The position in the queue that the entry was submitted at. Positions count up from 0 and
+    /// wrap at 2^31.
+    pub position: u32,
+    /// The index of the entry's slot in the buffer, which is the position modulo the queue's
+    /// length.
+    pub index: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Whether a submission woke the consumer. See [RawQueue::submit_notify].
+pub enum Notified {
+    /// The consumer was waiting, so the ring callback was called to wake it.
+    Rang,
+    /// The consumer wasn't waiting, or with coalesced wakes, another submitter had already woken
+    /// it, so the ring callback wasn't called.
+    Skipped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Whether a submission was added to the queue, or coalesced with the entry before it. See
+/// [RawQueue::submit_dedup].
+pub enum Submitted {
+    /// The entry was submitted to the queue.
+    Enqueued,
+    /// The last entry submitted had the same info tag and hadn't been received yet, so the entry
+    /// was dropped instead.
+    Coalesced,
+}
+
+/// The bit position in the info tag at which a producer id starts. Entries tagged with a producer
+/// id have the low bits of info available for the caller, and the remaining high bits hold the id.
+pub const PRODUCER_ID_SHIFT: u32 = Info::BITS - 8;
+
+/// The bits of the info tag left to the caller when an entry is tagged with a producer id.
+pub const PRODUCER_INFO_MASK: Info = (1 << PRODUCER_ID_SHIFT) - 1;
+
 /// The base info structure stored in a Twizzler queue object. Used to open Twizzler queue objects
 /// and create a [Queue].
 #[repr(C)]
@@ -154,186 +591,1102 @@ impl<S, C> BaseType for QueueBase<S, C> {
     }
 }
 
+/// The default number of times the queue will spin, waiting for a condition, before falling back
+/// to the wait callback. See [RawQueueHdr::with_spin_limit].
+const SPIN_ATTEMPTS: u32 = 1000;
+
+/// The number of those spins that are always plain spins, before a power-aware wait switches over
+/// to its idle callback.
+const IDLE_SPIN_ATTEMPTS: u32 = 16;
+
+#[inline]
+fn relax<I: Fn()>(limit: u32, attempts: u32, idle: &I) {
+    if limit - attempts <= IDLE_SPIN_ATTEMPTS {
+        core::hint::spin_loop();
+    } else {
+        idle();
+    }
+}
+
+/// A strategy for how a queue operation spins while it waits for a condition, before it falls back
+/// to the wait callback. See [RawQueue::submit_with_backoff] and [RawQueue::receive_with_backoff].
+pub trait Backoff {
+    /// Start over, at the beginning of a new wait.
+    fn reset(&mut self);
+    /// Spin once, or do whatever else the strategy does between checks of the condition. Returns
+    /// false once the caller should stop spinning and fall back to the wait callback.
+    fn spin(&mut self) -> bool;
+}
+
+impl<B: Backoff + ?Sized> Backoff for &mut B {
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn spin(&mut self) -> bool {
+        (**self).spin()
+    }
+}
+
+/// The queue's default backoff: spin up to a limit, and then fall back to the wait callback. After
+/// the first few spins, each spin calls an idle callback, which is a plain spin unless one is
+/// given with [SpinThenPark::with_idle].
+pub struct SpinThenPark<I: Fn() = fn()> {
+    limit: u32,
+    attempts: u32,
+    idle: I,
+}
+
+impl SpinThenPark {
+    /// Spin up to `limit` times before falling back to the wait callback.
+    pub fn new(limit: u32) -> Self {
+        Self::with_idle(limit, core::hint::spin_loop)
+    }
+}
+
+impl Default for SpinThenPark {
+    fn default() -> Self {
+        Self::new(SPIN_ATTEMPTS)
+    }
+}
+
+impl<I: Fn()> SpinThenPark<I> {
+    /// Spin up to `limit` times before falling back to the wait callback, calling `idle` instead of
+    /// busy-spinning after the first few spins. See [RawQueue::submit_with_idle].
+    pub fn with_idle(limit: u32, idle: I) -> Self {
+        Self {
+            limit,
+            attempts: limit,
+            idle,
+        }
+    }
+}
+
+impl<I: Fn()> Backoff for SpinThenPark<I> {
+    fn reset(&mut self) {
+        self.attempts = self.limit;
+    }
+
+    fn spin(&mut self) -> bool {
+        if self.attempts == 0 {
+            return false;
+        }
+        self.attempts -= 1;
+        relax(self.limit, self.attempts, &self.idle);
+        true
+    }
+}
+
+// Memory orderings for the queue protocol. By default, every access is SeqCst. With the
+// weak-ordering feature, the accesses that aren't part of a sleep/wake handshake use the weakest
+// ordering that keeps the protocol correct:
+//
+// - An entry is published by a Release store of its cmd_slot, after its data is written, and the
+//   consumer checks the turn bit with an Acquire load before reading the data. So a consumer that
+//   sees the turn bit flip also sees the data. (ORD_PUBLISH, ORD_OBSERVE)
+// - Outside of the sleep paths, the bell and tail are only used to decide where to look and whether
+//   to keep spinning. The turn check above is what makes an entry ready, and a submitter that loads
+//   the tail with Acquire synchronizes with the consumer's store of it, so the consumer's read of
+//   an old entry happens before the submitter overwrites that slot. (ORD_OBSERVE)
+// - Claiming slots on the head only needs the atomicity of the read-modify-write, since the head
+//   doesn't publish anything. (ORD_RESERVE)
+// - The consumer is the only writer of the tail's counter bits, so it can read its own tail with
+//   Relaxed. (ORD_OWN)
+//
+// Everything on the sleep/wake paths stays SeqCst: setting the consumer's waiting bit and then
+// rechecking the bell, against a submitter bumping the bell and then checking the waiting bit, is a
+// store-then-load handshake on two different words, and only SeqCst rules out both sides missing
+// each other's store (and so a lost wakeup). The same goes for the submitter waiters count against
+// the consumer's store of the tail, and so that store stays SeqCst as well.
+#[cfg(not(feature = "weak-ordering"))]
+const ORD_PUBLISH: Ordering = Ordering::SeqCst;
+#[cfg(not(feature = "weak-ordering"))]
+const ORD_OBSERVE: Ordering = Ordering::SeqCst;
+#[cfg(not(feature = "weak-ordering"))]
+const ORD_RESERVE: Ordering = Ordering::SeqCst;
+#[cfg(not(feature = "weak-ordering"))]
+const ORD_OWN: Ordering = Ordering::SeqCst;
+
+#[cfg(feature = "weak-ordering")]
+const ORD_PUBLISH: Ordering = Ordering::Release;
+#[cfg(feature = "weak-ordering")]
+const ORD_OBSERVE: Ordering = Ordering::Acquire;
+#[cfg(feature = "weak-ordering")]
+const ORD_RESERVE: Ordering = Ordering::Relaxed;
+#[cfg(feature = "weak-ordering")]
+const ORD_OWN: Ordering = Ordering::Relaxed;
+
+// Bits in the header's flags word.
+const HDR_FLAG_CONSUMER_PRESENT: u32 = 1;
+// Only the first submitter to see the consumer waiting wakes it, instead of every submitter that
+// sees it before the consumer gets going again.
+const HDR_FLAG_COALESCE_WAKES: u32 = 1 << 1;
+// The queue has been shut down, see RawQueueHdr::close.
+const HDR_FLAG_CLOSED: u32 = 1 << 2;
+
+// The spin budgets used by each of the queue modes.
+const LOW_LATENCY_SPIN_ATTEMPTS: u32 = 10000;
+const THROUGHPUT_SPIN_ATTEMPTS: u32 = 100;
+const POWER_SAVING_SPIN_ATTEMPTS: u32 = 0;
+
+// With the padded-header feature, the counters that producers write (the head and bell) and the
+// one that the consumer writes (the tail) each get a cache line to themselves, so that writes on
+// one side don't keep taking the other side's line away.
+#[cfg(feature = "padded-header")]
+#[repr(C, align(64))]
+struct CachePadded<T>(T);
+
+#[cfg(feature = "padded-header")]
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "padded-header")]
+type Padded<T> = CachePadded<T>;
+#[cfg(not(feature = "padded-header"))]
+type Padded<T> = T;
+
+#[inline]
+const fn pad<T>(x: T) -> Padded<T> {
+    #[cfg(feature = "padded-header")]
+    return CachePadded(x);
+    #[cfg(not(feature = "padded-header"))]
+    x
+}
+
 #[repr(C)]
 /// A raw queue header. This contains all the necessary counters and info to run the queue
 /// algorithm.
+///
+/// With the padded-header feature, the head, bell, and tail are each placed on their own 64-byte
+/// cache line, to avoid false sharing between producers and the consumer on busy queues. This
+/// makes the header several times larger, and changes its layout, so both sides of a queue must
+/// agree on it.
 pub struct RawQueueHdr {
     l2len: usize,
     stride: usize,
-    head: AtomicU32,
-    waiters: AtomicU32,
-    bell: AtomicU64,
-    tail: AtomicU64,
+    head: Padded<QAtomicU32>,
+    waiters: QAtomicU32,
+    bell: Padded<Counter>,
+    tail: Padded<Counter>,
+    flags: QAtomicU32,
+    spin_limit: QAtomicU32,
+    #[cfg(feature = "stats")]
+    wakes_issued: Counter,
+    #[cfg(feature = "stats")]
+    wakes_suppressed: Counter,
+    #[cfg(feature = "watermark")]
+    max_depth: QAtomicU32,
 }
 
-impl RawQueueHdr {
-    /// Construct a new raw queue header.
-    pub fn new(l2len: usize, stride: usize) -> Self {
-        Self {
-            l2len,
-            stride,
-            head: AtomicU32::new(0),
-            waiters: AtomicU32::new(0),
-            bell: AtomicU64::new(0),
-            tail: AtomicU64::new(0),
-        }
-    }
+// Likewise for the header. Without the padded-header feature, the fields are packed in order,
+// with the counters after the two words of geometry. With it, the head, bell, and tail each start
+// a cache line, and the waiters start the line after the head's.
+#[cfg(not(feature = "padded-header"))]
+const _: () = {
+    use core::mem::{offset_of, size_of};
+    const WORD: usize = size_of::<usize>();
+    const COUNTER: usize = size_of::<Counter>();
+    assert!(offset_of!(RawQueueHdr, l2len) == 0);
+    assert!(offset_of!(RawQueueHdr, stride) == WORD);
+    assert!(offset_of!(RawQueueHdr, head) == 2 * WORD);
+    assert!(offset_of!(RawQueueHdr, waiters) == 2 * WORD + 4);
+    assert!(offset_of!(RawQueueHdr, bell) == 2 * WORD + 8);
+    assert!(offset_of!(RawQueueHdr, tail) == 2 * WORD + 8 + COUNTER);
+    assert!(offset_of!(RawQueueHdr, flags) == 2 * WORD + 8 + 2 * COUNTER);
+    assert!(offset_of!(RawQueueHdr, spin_limit) == 2 * WORD + 12 + 2 * COUNTER);
+};
 
-    #[inline]
-    fn len(&self) -> usize {
-        1 << self.l2len
-    }
+#[cfg(feature = "padded-header")]
+const _: () = {
+    use core::mem::offset_of;
+    assert!(offset_of!(RawQueueHdr, l2len) == 0);
+    assert!(offset_of!(RawQueueHdr, head) == 64);
+    assert!(offset_of!(RawQueueHdr, waiters) == 128);
+    assert!(offset_of!(RawQueueHdr, bell) == 192);
+    assert!(offset_of!(RawQueueHdr, tail) == 256);
+    assert!(offset_of!(RawQueueHdr, flags) == 320);
+    assert!(offset_of!(RawQueueHdr, spin_limit) == 324);
+};
 
-    #[inline]
-    fn is_full(&self, h: u32, t: u64) -> bool {
-        (h & 0x7fffffff) as u64 - (t & 0x7fffffff) >= self.len() as u64
-    }
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Counts of how often submitters rang the doorbell, collected with the stats feature. See
+/// [RawQueueHdr::wake_stats].
+pub struct WakeStats {
+    /// The number of times a submitter called the ring callback to wake a waiting consumer.
+    pub wakes_issued: CounterValue,
+    /// The number of times a submitter skipped calling the ring callback, because the consumer
+    /// wasn't waiting.
+    pub wakes_suppressed: CounterValue,
+}
 
-    #[inline]
-    fn is_empty(&self, bell: u64, tail: u64) -> bool {
-        (bell & 0x7fffffff) == (tail & 0x7fffffff)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A snapshot of the state of a queue, for monitoring. See [RawQueueHdr::stats].
+pub struct QueueStats {
+    /// The position of the next slot to be reserved by a submitter.
+    pub head: u32,
+    /// The position of the next entry to be received by the consumer.
+    pub tail: CounterValue,
+    /// The doorbell, which counts the entries that have been published.
+    pub bell: CounterValue,
+    /// The number of entries the queue can hold.
+    pub len: usize,
+    /// The number of published entries that haven't been received yet.
+    pub occupancy: CounterValue,
+    /// Whether the consumer is waiting for an entry to arrive.
+    pub consumer_waiting: bool,
+    /// Whether any submitters are waiting for room in the queue.
+    pub submitters_waiting: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+/// A dump of the protocol state of a queue, for diagnosing a stuck queue. See
+/// [RawQueueHdr::debug_snapshot]. The Debug output lays this out for reading.
+pub struct HdrSnapshot {
+    /// The log2 of the length of the queue.
+    pub l2len: usize,
+    /// The size of each entry in the buffer.
+    pub stride: usize,
+    /// The position of the next slot to be reserved.
+    pub head: u32,
+    /// The position of the next entry to be received.
+    pub tail: CounterValue,
+    /// The doorbell, which counts the entries that have been published.
+    pub bell: CounterValue,
+    /// The number of submitters waiting for room.
+    pub waiters: u32,
+    /// The turn bit that the entry at the tail needs to have to be ready, which alternates each
+    /// time the tail wraps around the buffer.
+    pub turn: CounterValue,
+    /// The number of published entries that haven't been received yet.
+    pub occupancy: CounterValue,
+    /// Whether the consumer has marked itself as waiting.
+    pub consumer_waiting: bool,
+    /// Whether any submitters are waiting.
+    pub submitters_waiting: bool,
+    /// Whether the queue has been closed.
+    pub closed: bool,
+}
+
+impl core::fmt::Debug for HdrSnapshot {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "queue of {} entries (l2len {}), stride {}{}",
+            1usize << self.l2len,
+            self.l2len,
+            self.stride,
+            if self.closed { ", closed" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "  head {}, bell {}, tail {} (turn {}), {} published and not received",
+            self.head, self.bell, self.tail, self.turn, self.occupancy
+        )?;
+        write!(
+            f,
+            "  consumer {}, {} submitter(s) waiting",
+            if self.consumer_waiting {
+                "waiting"
+            } else {
+                "not waiting"
+            },
+            self.waiters
+        )
     }
+}
 
-    #[inline]
-    fn is_turn<T>(&self, t: u64, item: *const QueueEntry<T>) -> bool {
-        let turn = (t / (self.len() as u64)) % 2;
-        let val = unsafe { &*item }.get_cmd_slot() >> 31;
-        (val == 0) == (turn == 1)
+// Whether entries of type QueueEntry<T> can be placed stride bytes apart: the stride must hold a
+// whole entry and keep every entry aligned.
+fn stride_fits<T>(stride: usize) -> bool {
+    stride >= core::mem::size_of::<QueueEntry<T>>()
+        && stride % core::mem::align_of::<QueueEntry<T>>() == 0
+}
+
+/// The number of entries in the buffer of a queue with the given l2len, which is 2^l2len. This is
+/// the same as [RawQueueHdr::capacity] for a header made with that l2len, but usable in constant
+/// expressions, for example to size a buffer array: `[QueueEntry<T>; buffer_len(L2LEN)]`.
+pub const fn buffer_len(l2len: usize) -> usize {
+    1 << l2len
+}
+
+/// The largest number of entries a queue can hold, which is 2^[MAX_L2LEN].
+pub const fn max_capacity() -> usize {
+    buffer_len(MAX_L2LEN)
+}
+
+// The body of RawQueueHdr::new, which is a const fn except under loom.
+macro_rules! new_hdr {
+    ($l2len:expr, $stride:expr) => {{
+        assert!($l2len <= MAX_L2LEN, "queue too long");
+        RawQueueHdr {
+            l2len: $l2len,
+            stride: $stride,
+            head: pad(QAtomicU32::new(0)),
+            waiters: QAtomicU32::new(0),
+            bell: pad(Counter::new(0)),
+            tail: pad(Counter::new(0)),
+            flags: QAtomicU32::new(0),
+            spin_limit: QAtomicU32::new(SPIN_ATTEMPTS),
+            #[cfg(feature = "stats")]
+            wakes_issued: Counter::new(0),
+            #[cfg(feature = "stats")]
+            wakes_suppressed: Counter::new(0),
+            #[cfg(feature = "watermark")]
+            max_depth: QAtomicU32::new(0),
+        }
+    }};
+}
+
+impl RawQueueHdr {
+    /// Construct a new raw queue header, for a queue of 2^l2len entries of stride bytes each. Any
+    /// l2len from 0 (a single-slot queue, which works like a one-entry mailbox) to [MAX_L2LEN] is
+    /// supported. See [RawQueueHdr::new_checked] for a version that returns an error instead.
+    ///
+    /// # Panics
+    /// Panics if l2len is larger than [MAX_L2LEN].
+    #[cfg(not(loom))]
+    pub const fn new(l2len: usize, stride: usize) -> Self {
+        new_hdr!(l2len, stride)
     }
 
-    #[inline]
-    fn consumer_waiting(&self) -> bool {
-        (self.tail.load(Ordering::SeqCst) & (1 << 31)) != 0
+    /// Construct a new raw queue header. Loom's atomics can't be built in a const context, so
+    /// under loom this isn't a const fn.
+    #[cfg(loom)]
+    pub fn new(l2len: usize, stride: usize) -> Self {
+        new_hdr!(l2len, stride)
     }
 
-    #[inline]
-    fn submitter_waiting(&self) -> bool {
-        self.waiters.load(Ordering::SeqCst) > 0
+    /// Construct a new raw queue header, like [RawQueueHdr::new], with a different number of times
+    /// the queue will spin waiting for a condition before falling back to the wait callback (the
+    /// default is 1000). Longer spins are better for busy cores where waiting is expensive, and
+    /// shorter ones save power when the queue is often idle. A limit of 0 means to call wait
+    /// immediately, without spinning at all.
+    pub fn with_spin_limit(l2len: usize, stride: usize, spins: u32) -> Self {
+        let hdr = Self::new(l2len, stride);
+        hdr.spin_limit.store(spins, Ordering::Relaxed);
+        hdr
     }
 
-    #[inline]
-    fn consumer_set_waiting(&self, waiting: bool) {
-        if waiting {
-            self.tail.fetch_or(1 << 31, Ordering::SeqCst);
-        } else {
-            self.tail.fetch_and(!(1 << 31), Ordering::SeqCst);
-        }
+    /// Reset the queue's counters, so that a header in a reused region of memory starts over as an
+    /// empty queue. This also reopens a closed queue. Configuration, like the spin limit and the
+    /// mode, is kept.
+    ///
+    /// This is a reinitialization, not a concurrent clear: it's only correct if no producer or
+    /// consumer is using the queue, and none will until it returns. Anyone in the middle of an
+    /// operation would be left with positions that no longer mean anything. The buffer still holds
+    /// the old entries, with turn bits that may make them look ready, so it should be reset as
+    /// well, with [RawQueue::new_zeroed].
+    pub fn reset(&self) {
+        self.head.store(0, Ordering::SeqCst);
+        self.tail.store(0, Ordering::SeqCst);
+        self.bell.store(0, Ordering::SeqCst);
+        self.waiters.store(0, Ordering::SeqCst);
+        self.set_flag(HDR_FLAG_CLOSED, false);
     }
 
-    #[inline]
-    fn inc_submit_waiting(&self) {
-        self.waiters.fetch_add(1, Ordering::SeqCst);
+    /// Close the queue, for shutting down a connection. Once closed, submissions fail with
+    /// [QueueError::Closed], and so do receives once the consumer has taken every entry that was
+    /// published before the close. This can be called by anyone, including a thread that is
+    /// neither a producer nor the consumer.
+    ///
+    /// To release any threads already waiting in the queue, this changes both the word the
+    /// consumer waits on and the word submitters wait on, and then calls ring on each of them.
+    /// Waiters check the closed flag after reading the word they're about to wait on, so a thread
+    /// that is just about to wait either sees the flag or finds that the word has moved on, and
+    /// isn't left waiting for a ring that has already happened. Only bits outside the positions
+    /// change, but one of them is the consumer's waiting flag, so a closed queue may report the
+    /// consumer as waiting (or not) when it isn't.
+    pub fn close<R: Fn(&Counter)>(&self, ring: R) {
+        self.set_flag(HDR_FLAG_CLOSED, true);
+        self.bell.fetch_xor(CLOSE_TOGGLE, Ordering::SeqCst);
+        self.tail.fetch_xor(CLOSE_TOGGLE, Ordering::SeqCst);
+        ring(&self.bell);
+        ring(&self.tail);
     }
 
-    #[inline]
-    fn dec_submit_waiting(&self) {
-        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    /// Wake every thread parked in the queue, without submitting or receiving anything, so that
+    /// they re-check the state of the queue. If the consumer is waiting, this rings the bell, and
+    /// if any submitters are waiting, it rings the tail. No counters change, so this relies on
+    /// ring waking up waiters regardless (as a futex wake does). Threads that find nothing has
+    /// changed go back to waiting.
+    ///
+    /// Only threads that are already asleep are woken. A thread that is just about to wait may
+    /// miss a change made before this call and sleep anyway, so a change that waiters must notice
+    /// should also change the words they wait on, as [RawQueueHdr::close] does.
+    pub fn notify_all<R: Fn(&Counter)>(&self, ring: R) {
+        if self.consumer_waiting() {
+            self.trace("waking consumer");
+            ring(&self.bell);
+        }
+        if self.submitter_waiting() {
+            ring(&self.tail);
+        }
     }
 
-    #[inline]
-    fn reserve_slot<W: Fn(&AtomicU64, u64)>(
-        &self,
-        flags: SubmissionFlags,
-        wait: W,
-    ) -> Result<u32, QueueError> {
-        let h = self.head.fetch_add(1, Ordering::SeqCst);
+    /// Wait until the consumer has received every entry published to the queue, for example so
+    /// that a producer can make sure its work has been picked up before exiting. This returns
+    /// straight away if the queue is already empty. Otherwise, it waits like a submitter waiting
+    /// for room, so the consumer rings the tail as it makes progress. Any number of threads may
+    /// flush at once. Entries that have been reserved but not yet published aren't waited for.
+    ///
+    /// Returns Err([QueueError::Closed]) if the queue is closed before it's empty, since the
+    /// consumer may never get to the rest of it.
+    pub fn flush<W: Fn(&Counter, CounterValue)>(&self, wait: W) -> Result<(), QueueError> {
         let mut waiter = false;
-        let mut attempts = 1000;
-        loop {
+        let limit = self.spin_limit();
+        let mut attempts = limit;
+        let res = loop {
             let t = self.tail.load(Ordering::SeqCst);
-            if !self.is_full(h, t) {
-                break;
+            if self.is_empty(self.bell.load(Ordering::SeqCst), t) {
+                break Ok(());
             }
 
-            if flags.contains(SubmissionFlags::NON_BLOCK) {
-                return Err(QueueError::WouldBlock);
+            if self.is_closed() {
+                break Err(QueueError::Closed);
             }
 
             if attempts != 0 {
                 attempts -= 1;
-                core::hint::spin_loop();
+                relax(limit, attempts, &core::hint::spin_loop);
                 continue;
             }
 
+            // Register as waiting before the final check, so that the consumer either rings us
+            // or we see its progress.
             if !waiter {
                 waiter = true;
                 self.inc_submit_waiting();
+                continue;
             }
 
-            let t = self.tail.load(Ordering::SeqCst);
-            if self.is_full(h, t) {
-                wait(&self.tail, t);
-            }
-        }
+            self.wait_tail(&wait, t);
+        };
 
         if waiter {
             self.dec_submit_waiting();
         }
-
-        Ok(h & 0x7fffffff)
+        res
     }
 
-    #[inline]
-    fn get_turn(&self, h: u32) -> bool {
-        (h / self.len() as u32) % 2 == 0
+    /// The number of submitters currently waiting for room in the queue. Like the rest of the
+    /// queue's state, this may change as soon as it's read.
+    pub fn submitters_waiting(&self) -> u32 {
+        self.waiters.load(Ordering::SeqCst)
     }
 
+    /// Returns true if the consumer is currently waiting for an entry, in which case the next
+    /// submission will need to wake it. A producer might spin a bit longer before submitting to a
+    /// consumer that isn't waiting, since that won't cost a wake.
+    ///
+    /// This is a single load of the tail, so it's cheap enough to check before every submission,
+    /// for a producer that adapts its batching: submitting entries one at a time while the
+    /// consumer is awake and spinning, and batching them up (see [RawQueue::submit_batch]) while
+    /// it's parked, so that one wake covers the whole batch. With coalesced wakes (see
+    /// [QueueMode]), the first submitter to wake the consumer clears the flag, so this returns
+    /// false from then on even if the consumer hasn't run yet.
     #[inline]
-    fn ring<R: Fn(&AtomicU64)>(&self, ring: R) {
-        self.bell.fetch_add(1, Ordering::SeqCst);
-        if self.consumer_waiting() {
-            ring(&self.bell)
-        }
+    pub fn consumer_is_waiting(&self) -> bool {
+        self.consumer_waiting()
     }
 
-    #[inline]
-    fn get_next_ready<W: Fn(&AtomicU64, u64), T>(
-        &self,
-        wait: W,
-        flags: ReceiveFlags,
-        raw_buf: *const QueueEntry<T>,
-    ) -> Result<u64, QueueError> {
-        let mut attempts = 1000;
-        let t = self.tail.load(Ordering::SeqCst) & 0x7fffffff;
-        loop {
-            let b = self.bell.load(Ordering::SeqCst);
-            let item = unsafe { raw_buf.add((t as usize) & (self.len() - 1)) };
+    /// The bell word, which the consumer waits on while the queue is empty and submitters ring.
+    /// This is the same word that is passed to the wait and ring callbacks, so it can be handed to
+    /// an external wait mechanism ahead of time. The word is for waiting on and observing only:
+    /// writing to it directly corrupts the queue.
+    pub fn bell_word(&self) -> &Counter {
+        &self.bell
+    }
 
-            if !self.is_empty(b, t) && self.is_turn(t, item) {
-                break;
-            }
+    /// The tail word, which submitters wait on while the queue is full and the consumer rings. The
+    /// top bit of the word is the consumer's waiting flag, and the rest counts received entries.
+    /// Like [RawQueueHdr::bell_word], this is for waiting on and observing only.
+    pub fn tail_word(&self) -> &Counter {
+        &self.tail
+    }
 
-            if flags.contains(ReceiveFlags::NON_BLOCK) {
-                return Err(QueueError::WouldBlock);
-            }
+    /// The head word, which counts slots reserved by submitters. Nothing waits on the head, but it
+    /// can be watched to see submissions in progress. Like [RawQueueHdr::bell_word], this is for
+    /// observing only.
+    pub fn head_word(&self) -> &QAtomicU32 {
+        &self.head
+    }
 
-            if attempts != 0 {
-                attempts -= 1;
-                core::hint::spin_loop();
-                continue;
-            }
+    /// Returns true if the queue has been closed with [RawQueueHdr::close].
+    pub fn is_closed(&self) -> bool {
+        self.has_flag(HDR_FLAG_CLOSED)
+    }
 
-            self.consumer_set_waiting(true);
-            let b = self.bell.load(Ordering::SeqCst);
-            if self.is_empty(b, t) || !self.is_turn(t, item) {
-                wait(&self.bell, b);
-            }
+    /// Construct a new raw queue header, like [RawQueueHdr::new], but first check that the
+    /// arguments make sense for a queue of `QueueEntry<T>`: the stride must fit an entry and keep
+    /// every entry aligned, and the queue must have at most [max_capacity] entries.
+    pub fn new_checked<T>(l2len: usize, stride: usize) -> Result<Self, QueueLayoutError> {
+        if !stride_fits::<T>(stride) {
+            return Err(QueueLayoutError::StrideMismatch);
         }
-
-        if attempts == 0 {
+        if l2len >= usize::BITS as usize {
+            return Err(QueueLayoutError::CapacityOverflow);
+        }
+        if l2len > MAX_L2LEN {
+            return Err(QueueLayoutError::CapacityTooLarge);
+        }
+        Ok(Self::new(l2len, stride))
+    }
+
+    /// Construct a new raw queue header for a queue of `QueueEntry<T>` whose entries each start on
+    /// an `align`-byte boundary, such as for descriptors shared with a device that needs them
+    /// cache-line aligned. The stride is the size of an entry rounded up to the alignment (see
+    /// [RawQueue::aligned_stride]), and the buffer itself must be aligned to `align` as well (see
+    /// [RawQueue::layout_aligned]). Otherwise, this checks the arguments like
+    /// [RawQueueHdr::new_checked]. Fails with [QueueLayoutError::Misaligned] if `align` isn't a
+    /// power of two.
+    pub fn new_aligned<T>(l2len: usize, align: usize) -> Result<Self, QueueLayoutError> {
+        let stride = RawQueue::<T>::entry_layout(align)
+            .ok_or(QueueLayoutError::Misaligned)?
+            .size();
+        Self::new_checked::<T>(l2len, stride)
+    }
+
+    #[cfg(all(test, not(loom)))]
+    fn with_counters(l2len: usize, stride: usize, head: u32, bell: CounterValue) -> Self {
+        let hdr = Self::new(l2len, stride);
+        hdr.head.store(head, Ordering::SeqCst);
+        hdr.bell.store(bell, Ordering::SeqCst);
+        hdr.tail.store(bell & COUNTER_MASK, Ordering::SeqCst);
+        hdr
+    }
+
+    #[cfg(feature = "stats")]
+    /// Read the doorbell wake counters. Comparing the two shows what fraction of submissions
+    /// needed to wake the consumer (and so likely cost a syscall), versus found the consumer
+    /// already awake.
+    pub fn wake_stats(&self) -> WakeStats {
+        WakeStats {
+            wakes_issued: self.wakes_issued.load(Ordering::Relaxed),
+            wakes_suppressed: self.wakes_suppressed.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(feature = "watermark")]
+    /// The largest number of published entries that have been waiting in the queue at once, as
+    /// seen by submitters when they ring the bell, since the queue was created or the watermark
+    /// was last reset. Collected with the watermark feature, for checking whether a queue's length
+    /// fits its load.
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "watermark")]
+    /// Reset the watermark returned by [RawQueueHdr::max_depth] to zero.
+    pub fn reset_max_depth(&self) {
+        self.max_depth.store(0, Ordering::Relaxed);
+    }
+
+    // Raise the watermark to the depth of the queue, given the bell a submitter just rang.
+    #[cfg(feature = "watermark")]
+    #[allow(clippy::unnecessary_cast)] // not unnecessary without narrow-counters
+    #[inline]
+    fn record_depth(&self, bell: CounterValue) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let depth = bell.wrapping_sub(tail) & COUNTER_MASK;
+        self.max_depth.fetch_max(depth as u32, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the queue's counters, without performing any queue operations. This may
+    /// be called from any thread, not just the producers and consumer. The counters are each read
+    /// separately, so while the queue is busy the snapshot may be slightly inconsistent, but it's
+    /// good enough for monitoring.
+    pub fn stats(&self) -> QueueStats {
+        let head = self.head.load(Ordering::SeqCst) & POSITION_MASK;
+        let raw_tail = self.tail.load(Ordering::SeqCst);
+        let tail = raw_tail & COUNTER_MASK;
+        let bell = self.bell.load(Ordering::SeqCst) & COUNTER_MASK;
+        QueueStats {
+            head,
+            tail,
+            bell,
+            len: self.len(),
+            occupancy: bell.wrapping_sub(tail) & COUNTER_MASK,
+            consumer_waiting: raw_tail & CONSUMER_WAITING != 0,
+            submitters_waiting: self.submitter_waiting(),
+        }
+    }
+
+    /// Check that the counters in the header are consistent with each other: the tail is no further
+    /// along than the bell, the bell no further than the head, no more than a full queue's worth
+    /// of slots is reserved, and the waiting submitter count is sane. This is meant to be called
+    /// between operations in tests, since a submitter that is blocked on a full queue or in the
+    /// middle of publishing will make a correct queue look overfull.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        // Load in the order that the counters advance, so that a concurrent operation can't make
+        // the tail appear to pass the bell, or the bell the head.
+        let tail = self.tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        let bell = self.bell.load(Ordering::SeqCst) & COUNTER_MASK;
+        let head = self.head.load(Ordering::SeqCst) & POSITION_MASK;
+        let waiters = self.waiters.load(Ordering::SeqCst);
+
+        let published = bell.wrapping_sub(tail) & COUNTER_MASK;
+        let reserved = (head as CounterValue).wrapping_sub(tail) & COUNTER_MASK;
+        if published > COUNTER_MASK / 2 {
+            return Err(InvariantViolation::TailPastBell);
+        }
+        if published > reserved {
+            return Err(InvariantViolation::BellPastHead);
+        }
+        if reserved > self.len() as CounterValue {
+            return Err(InvariantViolation::Overfull);
+        }
+        if waiters > i32::MAX as u32 {
+            return Err(InvariantViolation::WaitersUnderflow);
+        }
+        Ok(())
+    }
+
+    /// Dump the queue's protocol state, for diagnosing a stuck queue. Like [RawQueueHdr::stats],
+    /// this doesn't change anything and may be called from any thread, and the counters are read
+    /// one after another, so the snapshot may be inconsistent if the queue is busy. Unlike stats,
+    /// this includes the raw state that the algorithm runs on, like the tail's turn.
+    pub fn debug_snapshot(&self) -> HdrSnapshot {
+        let head = self.head.load(Ordering::SeqCst) & POSITION_MASK;
+        let raw_tail = self.tail.load(Ordering::SeqCst);
+        let tail = raw_tail & COUNTER_MASK;
+        let bell = self.bell.load(Ordering::SeqCst) & COUNTER_MASK;
+        let waiters = self.waiters.load(Ordering::SeqCst);
+        HdrSnapshot {
+            l2len: self.l2len,
+            stride: self.stride,
+            head,
+            tail,
+            bell,
+            waiters,
+            turn: (tail / self.len() as CounterValue) % 2,
+            occupancy: bell.wrapping_sub(tail) & COUNTER_MASK,
+            consumer_waiting: raw_tail & CONSUMER_WAITING != 0,
+            submitters_waiting: waiters > 0,
+            closed: self.is_closed(),
+        }
+    }
+
+    /// The number of entries the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// The base-2 logarithm of the number of entries the queue can hold, as passed to
+    /// [RawQueueHdr::new].
+    pub fn l2_capacity(&self) -> usize {
+        self.l2len
+    }
+
+    /// The distance in bytes from each entry in the buffer to the next, as passed to
+    /// [RawQueueHdr::new]. This is usually the size of the entries, but may be larger to pad them
+    /// out to a stronger alignment (see [RawQueueHdr::new_aligned]).
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        1 << self.l2len
+    }
+
+    // The entry for a position in a buffer of this queue's entries, which are stride bytes apart.
+    #[inline]
+    fn slot<T>(&self, raw_buf: *const QueueEntry<T>, pos: usize) -> *mut QueueEntry<T> {
+        let off = (pos & (self.len() - 1)) * self.stride;
+        unsafe {
+            raw_buf
+                .cast::<u8>()
+                .add(off)
+                .cast::<QueueEntry<T>>()
+                .cast_mut()
+        }
+    }
+
+    #[inline]
+    fn is_full(&self, h: u32, t: CounterValue) -> bool {
+        // Both counters wrap, so the distance between them must be computed modulo the 31-bit
+        // counter space.
+        (h as CounterValue).wrapping_sub(t) & COUNTER_MASK >= self.len() as CounterValue
+    }
+
+    #[inline]
+    fn is_empty(&self, bell: CounterValue, tail: CounterValue) -> bool {
+        (bell & COUNTER_MASK) == (tail & COUNTER_MASK)
+    }
+
+    #[inline]
+    fn is_turn<T>(&self, t: CounterValue, item: *const QueueEntry<T>) -> bool {
+        let turn = (t / (self.len() as CounterValue)) % 2;
+        let val = unsafe { &*item }.get_cmd_slot() >> 31;
+        (val == 0) == (turn == 1)
+    }
+
+    #[inline]
+    fn set_flag(&self, flag: u32, set: bool) {
+        if set {
+            self.flags.fetch_or(flag, Ordering::SeqCst);
+        } else {
+            self.flags.fetch_and(!flag, Ordering::SeqCst);
+        }
+    }
+
+    #[inline]
+    fn has_flag(&self, flag: u32) -> bool {
+        self.flags.load(Ordering::SeqCst) & flag != 0
+    }
+
+    #[inline]
+    fn consumer_waiting(&self) -> bool {
+        (self.tail.load(Ordering::SeqCst) & CONSUMER_WAITING) != 0
+    }
+
+    // Check if the consumer is waiting and so needs a wake. When coalescing wakes, this also
+    // clears the waiting bit, so that only one submitter sees it. That's safe because the bell has
+    // already moved on, so the consumer will recheck the queue before it waits again.
+    #[inline]
+    fn consumer_needs_wake(&self) -> bool {
+        if self.has_flag(HDR_FLAG_COALESCE_WAKES) {
+            self.tail.fetch_and(!CONSUMER_WAITING, Ordering::SeqCst) & CONSUMER_WAITING != 0
+        } else {
+            self.consumer_waiting()
+        }
+    }
+
+    #[inline]
+    fn spin_limit(&self) -> u32 {
+        self.spin_limit.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn submitter_waiting(&self) -> bool {
+        self.waiters.load(Ordering::SeqCst) > 0
+    }
+
+    #[inline]
+    fn consumer_set_waiting(&self, waiting: bool) {
+        if waiting {
+            self.tail.fetch_or(CONSUMER_WAITING, Ordering::SeqCst);
+        } else {
+            self.tail.fetch_and(!CONSUMER_WAITING, Ordering::SeqCst);
+        }
+    }
+
+    #[inline]
+    fn inc_submit_waiting(&self) {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[inline]
+    fn dec_submit_waiting(&self) {
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // The backoff that the queue uses when the caller doesn't give one.
+    #[inline]
+    fn backoff(&self) -> SpinThenPark {
+        SpinThenPark::new(self.spin_limit())
+    }
+
+    #[inline]
+    fn reserve_slots<W: Fn(&Counter, CounterValue), B: Backoff>(
+        &self,
+        n: u32,
+        flags: SubmissionFlags,
+        wait: W,
+        backoff: &mut B,
+    ) -> Result<u32, QueueError> {
+        if n == 0 || n as usize > self.len() {
+            return Err(QueueError::Unknown);
+        }
+        if self.is_closed() {
+            return Err(QueueError::Closed);
+        }
+        // A blocking reserve takes its slots up front and then waits for them to be free, but a
+        // non-blocking one mustn't claim anything until it knows there's room, since it has no way
+        // to give the slots back if there isn't.
+        if flags.contains(SubmissionFlags::NON_BLOCK) {
+            return self.try_reserve_slots(n, n).map(|(h, _)| h);
+        }
+        let h = self.head.fetch_add(n, ORD_RESERVE);
+        let last = h.wrapping_add(n - 1);
+        let mut waiter = false;
+        backoff.reset();
+        let res = loop {
+            let t = self.tail.load(ORD_OBSERVE);
+            if !self.is_full(last, t) {
+                break Ok(h & POSITION_MASK);
+            }
+
+            if self.is_closed() {
+                break Err(QueueError::Closed);
+            }
+
+            if !waiter && backoff.spin() {
+                continue;
+            }
+
+            if !waiter {
+                waiter = true;
+                self.inc_submit_waiting();
+            }
+
+            let t = self.tail.load(Ordering::SeqCst);
+            if self.is_full(last, t) {
+                self.wait_tail(&wait, t);
+            }
+        };
+
+        if waiter {
+            self.dec_submit_waiting();
+        }
+        res
+    }
+
+    // Check that the entry at position t was actually submitted at that position, rather than
+    // being left over from an earlier use of the buffer with turn bits that happen to match.
+    #[cfg(feature = "debug-checks")]
+    #[allow(clippy::unnecessary_cast)] // not unnecessary without narrow-counters
+    #[inline]
+    fn check_slot<T>(&self, t: CounterValue, item: &QueueEntry<T>) -> Result<(), QueueError> {
+        if item.get_cmd_slot() & CMD_SLOT_INDEX_MASK != t as u32 & CMD_SLOT_INDEX_MASK {
+            return Err(QueueError::Corrupt);
+        }
+        Ok(())
+    }
+
+    // Reserve between `min` and `max` slots without blocking, returning the first reserved
+    // position and the number of slots reserved. Unlike a blocking reserve_slots, this never
+    // reserves slots that aren't free, so a failed attempt leaves the head untouched.
+    #[allow(clippy::unnecessary_cast)] // not unnecessary without narrow-counters
+    #[inline]
+    fn try_reserve_slots(&self, min: u32, max: u32) -> Result<(u32, u32), QueueError> {
+        if self.is_closed() {
+            return Err(QueueError::Closed);
+        }
+        let mut h = self.head.load(ORD_RESERVE);
+        loop {
+            let t = self.tail.load(ORD_OBSERVE);
+            let used = (h as CounterValue).wrapping_sub(t) & COUNTER_MASK;
+            let free = (self.len() as CounterValue).saturating_sub(used) as u32;
+            if free == 0 || free < min {
+                return Err(QueueError::WouldBlock);
+            }
+            let n = max.min(free);
+            match self
+                .head
+                .compare_exchange(h, h.wrapping_add(n), ORD_RESERVE, ORD_RESERVE)
+            {
+                Ok(_) => return Ok((h & POSITION_MASK, n)),
+                Err(cur) => h = cur,
+            }
+        }
+    }
+
+    // Reserve a single slot, like reserve_slots, but give up once the deadline has passed. To avoid
+    // leaving behind a reservation that will never be published, the head is only moved once
+    // there is room for the slot, so timing out doesn't claim anything.
+    fn reserve_slot_until<W: Fn(&Counter, CounterValue), D: Fn() -> bool>(
+        &self,
+        flags: SubmissionFlags,
+        wait: W,
+        deadline: D,
+    ) -> Result<u32, QueueError> {
+        let mut waiter = false;
+        let limit = self.spin_limit();
+        let mut attempts = limit;
+        let res = loop {
+            match self.try_reserve_slots(1, 1) {
+                Ok((h, _)) => break Ok(h),
+                Err(QueueError::WouldBlock) => {}
+                Err(e) => break Err(e),
+            }
+
+            if flags.contains(SubmissionFlags::NON_BLOCK) {
+                break Err(QueueError::WouldBlock);
+            }
+
+            if attempts != 0 {
+                attempts -= 1;
+                relax(limit, attempts, &core::hint::spin_loop);
+                continue;
+            }
+
+            if !waiter {
+                waiter = true;
+                self.inc_submit_waiting();
+            }
+
+            let t = self.tail.load(Ordering::SeqCst);
+            if self.is_full(self.head.load(Ordering::SeqCst), t) {
+                if deadline() {
+                    break Err(QueueError::TimedOut);
+                }
+                self.wait_tail(&wait, t);
+            }
+        };
+
+        if waiter {
+            self.dec_submit_waiting();
+        }
+        res
+    }
+
+    #[inline]
+    fn get_turn(&self, h: u32) -> bool {
+        (h / self.len() as u32) % 2 == 0
+    }
+
+    #[inline]
+    fn cmd_slot(&self, h: u32, abandoned: bool) -> u32 {
+        let mut cmd_slot = h & CMD_SLOT_INDEX_MASK;
+        if abandoned {
+            cmd_slot |= CMD_SLOT_ABANDONED;
+        }
+        if self.get_turn(h) {
+            cmd_slot |= CMD_SLOT_TURN;
+        }
+        cmd_slot
+    }
+
+    #[inline]
+    fn ring<R: Fn(&Counter)>(&self, ring: R) -> bool {
+        self.ring_many(1, ring)
+    }
+
+    // With the tracing feature, emit a trace event for something happening on the queue, along
+    // with the queue's counters, so that a trace can be used to follow the queue's progress.
+    #[inline]
+    fn trace(&self, _event: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            head = self.head.load(Ordering::Relaxed) & POSITION_MASK,
+            bell = self.bell.load(Ordering::Relaxed) & COUNTER_MASK,
+            tail = self.tail.load(Ordering::Relaxed) & COUNTER_MASK,
+            "{}",
+            _event
+        );
+    }
+
+    // Wait, as a submitter, for the tail to move on from t. The caller must have read t before
+    // this checks whether the queue is closed, so that a close either shows up here or changes
+    // the tail (see RawQueueHdr::close). Either way, the caller's next look at the queue sees it.
+    #[inline]
+    fn wait_tail<W: Fn(&Counter, CounterValue)>(&self, wait: W, t: CounterValue) {
+        if self.is_closed() {
+            return;
+        }
+        self.trace("submitter parking");
+        wait(&self.tail, t);
+        self.trace("submitter unparked");
+    }
+
+    // Wait, as the consumer, for the bell to move on from b. As with wait_tail, the caller must
+    // have read b first.
+    #[inline]
+    fn wait_bell<W: Fn(&Counter, CounterValue)>(&self, wait: W, b: CounterValue) {
+        if self.is_closed() {
+            return;
+        }
+        self.trace("consumer parking");
+        wait(&self.bell, b);
+        self.trace("consumer unparked");
+    }
+
+    // Bump the bell for n newly published entries, and wake the consumer if it needs it. Returns
+    // true if ring was called.
+    #[inline]
+    fn ring_many<R: Fn(&Counter)>(&self, n: u32, ring: R) -> bool {
+        let _bell = self.bell.fetch_add(n as CounterValue, Ordering::SeqCst);
+        #[cfg(feature = "watermark")]
+        self.record_depth(_bell.wrapping_add(n as CounterValue));
+        if self.consumer_needs_wake() {
+            #[cfg(feature = "stats")]
+            self.wakes_issued.fetch_add(1, Ordering::Relaxed);
+            self.trace("waking consumer");
+            ring(&self.bell);
+            true
+        } else {
+            #[cfg(feature = "stats")]
+            self.wakes_suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    #[inline]
+    fn get_next_ready<W: Fn(&Counter, CounterValue), B: Backoff, D: Fn() -> bool, T>(
+        &self,
+        wait: W,
+        backoff: &mut B,
+        deadline: D,
+        flags: ReceiveFlags,
+        raw_buf: *const QueueEntry<T>,
+    ) -> Result<CounterValue, QueueError> {
+        let mut waiting = false;
+        backoff.reset();
+        let t = self.tail.load(ORD_OWN) & COUNTER_MASK;
+        loop {
+            let b = self.bell.load(ORD_OBSERVE);
+            let item = self.slot(raw_buf, t as usize);
+
+            if !self.is_empty(b, t) && self.is_turn(t, item) {
+                break;
+            }
+
+            if self.is_closed() {
+                if waiting {
+                    self.consumer_set_waiting(false);
+                }
+                return Err(QueueError::Closed);
+            }
+
+            if flags.contains(ReceiveFlags::NON_BLOCK) {
+                // If a submitter has reserved the slot at the tail, the queue isn't empty, just not
+                // ready yet.
+                if flags.contains(ReceiveFlags::REPORT_NOT_READY)
+                    && !self.is_empty(self.head.load(Ordering::SeqCst) as CounterValue, t)
+                {
+                    return Err(QueueError::NotReady);
+                }
+                return Err(QueueError::WouldBlock);
+            }
+
+            if !waiting && backoff.spin() {
+                continue;
+            }
+
+            waiting = true;
+            self.consumer_set_waiting(true);
+            let b = self.bell.load(Ordering::SeqCst);
+            if self.is_empty(b, t) || !self.is_turn(t, item) {
+                if deadline() {
+                    // Nobody is going to be waiting on the bell, so don't make submitters ring it.
+                    self.consumer_set_waiting(false);
+                    return Err(QueueError::TimedOut);
+                }
+                self.wait_bell(&wait, b);
+            }
+        }
+
+        if waiting {
             self.consumer_set_waiting(false);
         }
         Ok(t)
     }
 
-    fn setup_rec_sleep_simple(&self) -> (&AtomicU64, u64) {
+    fn setup_rec_sleep_simple(&self) -> (&Counter, CounterValue) {
         // TODO: an interface that undoes this.
         self.consumer_set_waiting(true);
         let b = self.bell.load(Ordering::SeqCst);
         (&self.bell, b)
     }
 
-    fn setup_send_sleep_simple(&self) -> (&AtomicU64, u64) {
+    fn setup_send_sleep_simple(&self) -> (&Counter, CounterValue) {
         // TODO: an interface that undoes this.
         self.submitter_waiting();
         let t = self.tail.load(Ordering::SeqCst);
@@ -344,13 +1697,16 @@ impl RawQueueHdr {
         &'a self,
         sleep: bool,
         raw_buf: *const QueueEntry<T>,
-        waiter: &mut (Option<&'a AtomicU64>, u64),
-    ) -> Result<u64, QueueError> {
-        let t = self.tail.load(Ordering::SeqCst) & 0x7fffffff;
-        let b = self.bell.load(Ordering::SeqCst);
-        let item = unsafe { raw_buf.add((t as usize) & (self.len() - 1)) };
+        waiter: &mut (Option<&'a Counter>, CounterValue),
+    ) -> Result<CounterValue, QueueError> {
+        let t = self.tail.load(ORD_OWN) & COUNTER_MASK;
+        let b = self.bell.load(ORD_OBSERVE);
+        let item = self.slot(raw_buf, t as usize);
         *waiter = (Some(&self.bell), b);
         if self.is_empty(b, t) || !self.is_turn(t, item) {
+            if self.is_closed() {
+                return Err(QueueError::Closed);
+            }
             if sleep {
                 self.consumer_set_waiting(true);
                 let b = self.bell.load(Ordering::SeqCst);
@@ -358,6 +1714,10 @@ impl RawQueueHdr {
                 if !self.is_empty(b, t) && self.is_turn(t, item) {
                     return Ok(t);
                 }
+                // As in wait_bell, a close after this point changes the bell.
+                if self.is_closed() {
+                    return Err(QueueError::Closed);
+                }
             }
             Err(QueueError::WouldBlock)
         } else {
@@ -366,41 +1726,168 @@ impl RawQueueHdr {
     }
 
     #[inline]
-    fn advance_tail<R: Fn(&AtomicU64)>(&self, ring: R) {
-        let t = self.tail.load(Ordering::SeqCst);
-        self.tail.store((t + 1) & 0x7fffffff, Ordering::SeqCst);
+    fn get_ready_ahead<T>(
+        &self,
+        n: usize,
+        raw_buf: *const QueueEntry<T>,
+    ) -> Result<CounterValue, QueueError> {
+        if n >= self.len() {
+            return Err(QueueError::WouldBlock);
+        }
+        let t = self.tail.load(ORD_OBSERVE) & COUNTER_MASK;
+        let b = self.bell.load(ORD_OBSERVE);
+        let pos = (t + n as CounterValue) & COUNTER_MASK;
+        let item = self.slot(raw_buf, pos as usize);
+        if (b.wrapping_sub(t) & COUNTER_MASK) as usize > n && self.is_turn(pos, item) {
+            Ok(pos)
+        } else {
+            Err(QueueError::WouldBlock)
+        }
+    }
+
+    #[inline]
+    fn advance_tail<R: Fn(&Counter)>(&self, ring: R) {
+        let t = self.tail.load(ORD_OWN);
+        self.tail
+            .store(t.wrapping_add(1) & COUNTER_MASK, Ordering::SeqCst);
         if self.submitter_waiting() {
             ring(&self.tail);
         }
     }
 
     #[inline]
-    fn advance_tail_setup<'a>(&'a self, ringer: &mut Option<&'a AtomicU64>) {
-        let t = self.tail.load(Ordering::SeqCst);
-        self.tail.store((t + 1) & 0x7fffffff, Ordering::SeqCst);
+    fn advance_tail_setup<'a>(&'a self, ringer: &mut Option<&'a Counter>) {
+        let t = self.tail.load(ORD_OWN);
+        self.tail
+            .store(t.wrapping_add(1) & COUNTER_MASK, Ordering::SeqCst);
         if self.submitter_waiting() {
             *ringer = Some(&self.tail);
         }
     }
 }
 
-/// A raw queue, comprising of a header to track the algorithm and a buffer to hold queue entries.
-pub struct RawQueue<T> {
-    hdr: *const RawQueueHdr,
-    buf: UnsafeCell<*mut QueueEntry<T>>,
+/// A cursor for the consumer to drain a run of ready entries. Since the consumer is the only
+/// writer of the tail, the cursor can keep the tail (and the last bell value it saw) locally for
+/// the whole run, and only write the tail back to the header once, when the run is finished.
+///
+/// The tail is written back when the cursor is dropped, including while unwinding from a panic
+/// in the consumer partway through the run, so the entries it has already handed out stay
+/// received and their slots are released to the producers.
+struct DrainCursor<'a, R: Fn(&Counter)> {
+    hdr: &'a RawQueueHdr,
+    start: CounterValue,
+    tail: CounterValue,
+    bell: CounterValue,
+    ring: R,
 }
 
-bitflags::bitflags! {
+impl<'a, R: Fn(&Counter)> DrainCursor<'a, R> {
+    fn new(hdr: &'a RawQueueHdr, ring: R) -> Self {
+        let tail = hdr.tail.load(ORD_OWN) & COUNTER_MASK;
+        Self {
+            hdr,
+            start: tail,
+            tail,
+            bell: tail,
+            ring,
+        }
+    }
+
+    // Find the position of the next ready entry, without taking it.
+    #[inline]
+    fn next_ready<T>(&mut self, raw_buf: *const QueueEntry<T>) -> Option<CounterValue> {
+        if self.hdr.is_empty(self.bell, self.tail) {
+            self.bell = self.hdr.bell.load(ORD_OBSERVE);
+            if self.hdr.is_empty(self.bell, self.tail) {
+                return None;
+            }
+        }
+        let t = self.tail;
+        let item = self.hdr.slot(raw_buf, t as usize);
+        if !self.hdr.is_turn(t, item) {
+            return None;
+        }
+        Some(t)
+    }
+
+    // Take the entry found by next_ready.
+    #[inline]
+    fn advance(&mut self) {
+        self.tail = (self.tail + 1) & COUNTER_MASK;
+    }
+}
+
+impl<'a, R: Fn(&Counter)> Drop for DrainCursor<'a, R> {
+    fn drop(&mut self) {
+        if self.tail == self.start {
+            return;
+        }
+        self.hdr.tail.store(self.tail, Ordering::SeqCst);
+        if self.hdr.submitter_waiting() {
+            (self.ring)(&self.hdr.tail);
+        }
+    }
+}
+
+/// A raw queue, comprising of a header to track the algorithm and a buffer to hold queue entries.
+///
+/// The data type doesn't have to be Copy for submitting and receiving: entries are moved into the
+/// buffer on submit, and moved back out on receive, so a payload that owns memory or implements
+/// Drop is dropped exactly once, by whoever receives it. Entries still in the queue when it's torn
+/// down are leaked. Such payloads are only safe when the buffer is private to a single address
+/// space, since a payload that's readable from elsewhere would either carry pointers that mean
+/// nothing there, or be duplicated behind our back. The functions that copy entries rather than
+/// moving them (peeking, batches, and the like) still require Copy.
+pub struct RawQueue<T> {
+    hdr: *const RawQueueHdr,
+    // The buffer pointer is written once at construction and never changes afterwards, so reading
+    // it needs no synchronization. If the buffer ever becomes rebindable, this must become an
+    // AtomicPtr with acquire loads and a release store.
+    buf: *mut QueueEntry<T>,
+    // Set while the consumer is inside a receive operation, to catch reentrant receives (e.g. from
+    // a signal handler that interrupted the consumer).
+    receiving: AtomicBool,
+}
+
+/// Clears the consumer's receiving flag when a receive operation finishes.
+struct ConsumerGuard<'a>(&'a AtomicBool);
+
+impl<'a> ConsumerGuard<'a> {
+    #[inline]
+    fn enter(receiving: &'a AtomicBool) -> Result<Self, QueueError> {
+        if receiving.swap(true, Ordering::Acquire) {
+            if cfg!(feature = "debug-checks") {
+                panic!("reentrant receive on a raw queue");
+            }
+            return Err(QueueError::Reentrant);
+        }
+        Ok(ConsumerGuard(receiving))
+    }
+}
+
+impl<'a> Drop for ConsumerGuard<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     /// Flags to control how queue submission works.
     pub struct SubmissionFlags: u32 {
         /// If the request would block, return Err([SubmissionError::WouldBlock]) instead.
         const NON_BLOCK = 1;
     }
 
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     /// Flags to control how queue receive works.
     pub struct ReceiveFlags: u32 {
         /// If the request would block, return Err([ReceiveError::WouldBlock]) instead.
         const NON_BLOCK = 1;
+        /// With NON_BLOCK, return Err([QueueError::NotReady]) instead of WouldBlock if the next
+        /// entry has been reserved but not yet published, so the caller can tell that apart from an
+        /// empty queue.
+        const REPORT_NOT_READY = 2;
     }
 }
 
@@ -411,283 +1898,4502 @@ pub enum QueueError {
     Unknown,
     /// The operation would have blocked, and non-blocking operation was specified.
     WouldBlock,
+    /// A receive operation was started while another receive on the same queue was still in
+    /// progress, for example from a signal handler that interrupted the consumer.
+    Reentrant,
+    /// The entry at the front of the queue looks ready, but wasn't submitted at the position it
+    /// was found at. This usually means that the buffer held stale data from a previous use and
+    /// wasn't reset (see [RawQueue::new_zeroed]). Only detected with the debug-checks feature.
+    ///
+    /// With the checksum feature, this also means that the entry's contents don't match the
+    /// checksum its submitter stored, so the entry was changed after it was published. Either way,
+    /// the entry is left at the front of the queue.
+    Corrupt,
+    /// The operation's deadline passed before it could complete.
+    TimedOut,
+    /// The queue has been closed with [RawQueueHdr::close]. Submissions fail straight away, while
+    /// receives still return any entries that were published before the queue was closed.
+    Closed,
+    /// A non-blocking receive found that the next entry has been reserved by a submitter, but not
+    /// yet published. Only returned when asked for with [ReceiveFlags::REPORT_NOT_READY], in which
+    /// case [QueueError::WouldBlock] means that the queue is empty. The entry is usually on its
+    /// way, so a polling consumer may want to retry sooner than it would on an empty queue.
+    NotReady,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// Possible errors from checking the layout of a queue, with [RawQueueHdr::new_checked] or
+/// [RawQueue::new_validated].
+pub enum QueueLayoutError {
+    /// The stride is smaller than the queue's entries, or doesn't keep them aligned.
+    StrideMismatch,
+    /// The queue is too long for the counters to track: there must be at most [max_capacity]
+    /// entries.
+    CapacityTooLarge,
+    /// The number of entries doesn't fit in a usize.
+    CapacityOverflow,
+    /// The buffer is too small to hold every entry of the queue.
+    BufferTooSmall,
+    /// The buffer isn't aligned for the queue's entries, or a requested alignment isn't a power of
+    /// two.
+    Misaligned,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// The protocol invariants that [RawQueueHdr::check_invariants] and [RawQueue::check_invariants]
+/// can find violated.
+pub enum InvariantViolation {
+    /// The tail has moved past the bell, so the consumer has received entries that were never
+    /// published.
+    TailPastBell,
+    /// The bell has moved past the head, so more entries have been published than reserved.
+    BellPastHead,
+    /// More entries are reserved than there are slots in the queue.
+    Overfull,
+    /// The count of waiting submitters has underflowed.
+    WaitersUnderflow,
+    /// A published entry's turn bit doesn't match the turn of its position in the queue.
+    EntryOutOfTurn,
+    /// A published entry records a different position than the one it was found at.
+    EntryMisplaced,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A named bundle of performance tunings for a queue, set with [RawQueue::set_mode].
+pub enum QueueMode {
+    /// Spin for a long time before waiting, and wake the consumer from every submitter that sees
+    /// it waiting. Burns CPU to keep the latency of each entry down.
+    LowLatency,
+    /// Spin briefly before waiting, and coalesce wakes so that the consumer is woken at most once
+    /// each time it waits, however many submissions arrive in the meantime.
+    Throughput,
+    /// Don't spin at all, going straight to the wait callback, and coalesce wakes.
+    PowerSaving,
+}
+
+impl QueueMode {
+    fn spin_limit(&self) -> u32 {
+        match self {
+            QueueMode::LowLatency => LOW_LATENCY_SPIN_ATTEMPTS,
+            QueueMode::Throughput => THROUGHPUT_SPIN_ATTEMPTS,
+            QueueMode::PowerSaving => POWER_SAVING_SPIN_ATTEMPTS,
+        }
+    }
+
+    fn coalesce_wakes(&self) -> bool {
+        !matches!(self, QueueMode::LowLatency)
+    }
+}
+
+impl<T> RawQueue<T> {
+    #[inline]
+    fn hdr(&self) -> &RawQueueHdr {
+        unsafe { &*self.hdr }
+    }
+
+    // This is a bit unsafe, but it's because we're managing concurrency ourselves.
+    #[allow(clippy::mut_from_ref)]
+    #[inline]
+    fn get_buf(&self, off: usize) -> &mut QueueEntry<T> {
+        unsafe { self.hdr().slot(self.buf, off).as_mut().unwrap() }
+    }
+
+    // The layout of one entry padded out to the given alignment, or None if the alignment isn't a
+    // power of two.
+    fn entry_layout(align: usize) -> Option<core::alloc::Layout> {
+        let entry = core::alloc::Layout::new::<QueueEntry<T>>();
+        let layout = core::alloc::Layout::from_size_align(entry.size(), align.max(entry.align()));
+        Some(layout.ok()?.pad_to_align())
+    }
+
+    // The layout of a header followed by a buffer of 2^l2len entries, each aligned to align, and
+    // the offset of the buffer.
+    fn region_layout(l2len: usize, align: usize) -> (core::alloc::Layout, usize) {
+        let entry = Self::entry_layout(align).expect("alignment must be a power of two");
+        let size = entry
+            .size()
+            .checked_mul(1 << l2len)
+            .expect("queue buffer too large");
+        let buf = core::alloc::Layout::from_size_align(size, entry.align())
+            .expect("queue buffer too large");
+        let (layout, offset) = core::alloc::Layout::new::<RawQueueHdr>()
+            .extend(buf)
+            .expect("queue buffer too large");
+        (layout.pad_to_align(), offset)
+    }
+
+    /// The stride of a queue whose entries are each aligned to `align` bytes: the size of an entry
+    /// rounded up to the alignment. See [RawQueueHdr::new_aligned].
+    ///
+    /// # Panics
+    /// Panics if `align` isn't a power of two.
+    pub fn aligned_stride(align: usize) -> usize {
+        Self::entry_layout(align)
+            .expect("alignment must be a power of two")
+            .size()
+    }
+
+    /// The memory layout of a region holding a [RawQueueHdr] immediately followed by a buffer of
+    /// 2^l2len entries, including any padding needed to align the buffer. This is the amount of
+    /// memory to set aside when mapping a queue as a single object.
+    ///
+    /// # Panics
+    /// Panics if the region's size would overflow.
+    pub fn layout(l2len: usize) -> core::alloc::Layout {
+        Self::region_layout(l2len, 1).0
+    }
+
+    /// The memory layout of a region holding a [RawQueueHdr] followed by a buffer of 2^l2len
+    /// entries that are each aligned to `align` bytes, as for a header made with
+    /// [RawQueueHdr::new_aligned].
+    ///
+    /// # Panics
+    /// Panics if the region's size would overflow, or if `align` isn't a power of two.
+    pub fn layout_aligned(l2len: usize, align: usize) -> core::alloc::Layout {
+        Self::region_layout(l2len, align).0
+    }
+
+    /// The offset of the buffer from the start of a region laid out as in [RawQueue::layout].
+    ///
+    /// # Panics
+    /// Panics if the region's size would overflow.
+    pub fn buffer_offset(l2len: usize) -> usize {
+        Self::region_layout(l2len, 1).1
+    }
+
+    /// The offset of the buffer from the start of a region laid out as in
+    /// [RawQueue::layout_aligned].
+    ///
+    /// # Panics
+    /// Panics if the region's size would overflow, or if `align` isn't a power of two.
+    pub fn buffer_offset_aligned(l2len: usize, align: usize) -> usize {
+        Self::region_layout(l2len, align).1
+    }
+
+    /// The number of entries the queue can hold. See [RawQueueHdr::capacity].
+    pub fn capacity(&self) -> usize {
+        self.hdr().capacity()
+    }
+
+    /// The base-2 logarithm of the queue's capacity. See [RawQueueHdr::l2_capacity].
+    pub fn l2_capacity(&self) -> usize {
+        self.hdr().l2_capacity()
+    }
+
+    /// The distance in bytes between entries in the buffer. See [RawQueueHdr::stride].
+    pub fn stride(&self) -> usize {
+        self.hdr().stride()
+    }
+
+    /// Check whether the queue has no published entries waiting to be received. This is only a
+    /// hint, since producers and the consumer may change the state of the queue at any moment,
+    /// but it's a cheap way for a polling consumer to decide whether to try receiving at all.
+    pub fn is_empty(&self) -> bool {
+        let hdr = self.hdr();
+        hdr.is_empty(
+            hdr.bell.load(Ordering::SeqCst),
+            hdr.tail.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Check whether every slot in the queue is taken, either by an entry waiting to be received
+    /// or by a submission in progress. Like [RawQueue::is_empty], this is only a hint.
+    pub fn is_full(&self) -> bool {
+        let hdr = self.hdr();
+        hdr.is_full(
+            hdr.head.load(Ordering::SeqCst) & POSITION_MASK,
+            hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK,
+        )
+    }
+
+    /// The turn bit that the entry at queue position `t` must carry for the consumer to take it
+    /// when its tail reaches `t`. The turn flips each time the positions wrap around the buffer,
+    /// which is how the consumer tells a freshly published entry from a stale one left over from
+    /// the previous lap.
+    pub fn expected_turn_at(&self, t: CounterValue) -> bool {
+        (t / self.hdr().len() as CounterValue) % 2 == 0
+    }
+
+    /// Check whether the slot for queue position `t` carries the turn bit the consumer expects
+    /// there (see [RawQueue::expected_turn_at]). This is the consumer's readiness check on the
+    /// entry itself, separate from whether the bell says anything has been published. When a
+    /// consumer is stuck waiting at its tail, an empty queue (the bell equals the tail) with a
+    /// ready slot is fine, while a bell past the tail with a slot that isn't ready means a
+    /// producer reserved the slot but hasn't published it, or wrote the wrong turn.
+    ///
+    /// Like the other inspection functions, this is only a snapshot.
+    pub fn slot_is_ready(&self, t: CounterValue) -> bool {
+        let hdr = self.hdr();
+        hdr.is_turn(t, hdr.slot(self.buf, t as usize))
+    }
+
+    /// Check the header's invariants, with [RawQueueHdr::check_invariants], and then check that
+    /// every published entry between the tail and the bell carries the turn bit and position
+    /// that the tail's progress says it should. As with the header check, this is only reliable
+    /// while no operations are in flight.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let hdr = self.hdr();
+        hdr.check_invariants()?;
+        let tail = hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        let bell = hdr.bell.load(Ordering::SeqCst) & COUNTER_MASK;
+        let mut t = tail;
+        while t != bell {
+            let item = self.get_buf(t as usize);
+            if !hdr.is_turn(t, item) {
+                return Err(InvariantViolation::EntryOutOfTurn);
+            }
+            let index = (item.get_cmd_slot() & CMD_SLOT_INDEX_MASK) as CounterValue;
+            if index != t & CMD_SLOT_INDEX_MASK as CounterValue {
+                return Err(InvariantViolation::EntryMisplaced);
+            }
+            t = t.wrapping_add(1) & COUNTER_MASK;
+        }
+        Ok(())
+    }
 }
 
-impl<T: Copy> RawQueue<T> {
-    /// Construct a new raw queue out of a header reference and a buffer pointer.
+impl<T: EntryData> RawQueue<T> {
+    /// Construct a new raw queue out of a header pointer and a buffer pointer. Both are raw
+    /// pointers, and the queue doesn't borrow them, so a header and buffer found in a shared
+    /// object can be passed straight in, without first making references to them. The caller
+    /// chooses how long the queue lives, as set out below.
     /// # Safety
-    /// The caller must ensure that hdr and buf point to valid objects, and that the lifetime of the
-    /// RawQueue is exceeded by the objects pointed to.
+    /// The caller must ensure that hdr points to an initialized header (made by
+    /// [RawQueueHdr::new], possibly by another party to the queue), and that buf points to an
+    /// array of as many entries as the header's [RawQueueHdr::capacity], suitably aligned. Both
+    /// must stay valid, and mapped at the same addresses, for as long as the RawQueue is used. The
+    /// header's stride must fit the entries and keep them aligned (this is checked in debug
+    /// builds). See [RawQueue::new_validated] to check the buffer against the header.
     pub unsafe fn new(hdr: *const RawQueueHdr, buf: *mut QueueEntry<T>) -> Self {
+        debug_assert!(
+            stride_fits::<T>((*hdr).stride()),
+            "queue stride doesn't fit the entries"
+        );
         Self {
             hdr,
-            buf: UnsafeCell::new(buf),
+            buf,
+            receiving: AtomicBool::new(false),
+        }
+    }
+
+    /// Construct a new raw queue, like [RawQueue::new], but first check that the buffer matches
+    /// the header: the header's stride must fit the entries and keep them aligned, and the
+    /// buffer, which is `buf_len` bytes long, must be big enough for every entry of the queue and
+    /// suitably aligned.
+    /// This catches mismatched mappings up front, instead of as memory corruption later on.
+    /// # Safety
+    /// The caller must ensure that hdr points to a valid header, and that buf points to at least
+    /// buf_len bytes, for the lifetime of the RawQueue.
+    pub unsafe fn new_validated(
+        hdr: *const RawQueueHdr,
+        buf: *mut QueueEntry<T>,
+        buf_len: usize,
+    ) -> Result<Self, QueueLayoutError> {
+        let h = &*hdr;
+        if !stride_fits::<T>(h.stride()) {
+            return Err(QueueLayoutError::StrideMismatch);
+        }
+        let needed = h
+            .capacity()
+            .checked_mul(h.stride())
+            .ok_or(QueueLayoutError::CapacityOverflow)?;
+        if buf_len < needed {
+            return Err(QueueLayoutError::BufferTooSmall);
+        }
+        if (buf as usize) % core::mem::align_of::<QueueEntry<T>>() != 0 {
+            return Err(QueueLayoutError::Misaligned);
+        }
+        Ok(Self::new(hdr, buf))
+    }
+
+    /// Construct a new raw queue, like [RawQueue::new], but first reset the turn state of every
+    /// entry in the buffer. This is the recommended way to set up a queue over a buffer that
+    /// might hold stale data from a previous use, since leftover turn bits can make a slot that
+    /// hasn't been written yet look ready to the consumer. Only the internal bookkeeping of each
+    /// entry is reset, not the info tag or data.
+    /// # Safety
+    /// In addition to the requirements of [RawQueue::new], the header must be freshly constructed
+    /// and no one else may be using the queue yet.
+    pub unsafe fn new_zeroed(hdr: *const RawQueueHdr, buf: *mut QueueEntry<T>) -> Self {
+        for i in 0..(*hdr).len() {
+            core::ptr::addr_of_mut!((*(*hdr).slot(buf, i)).cmd_slot).write(new_cmd_slot(0));
+        }
+        Self::new(hdr, buf)
+    }
+
+    /// Initialize a buffer of fresh, uninitialized memory for use by a queue, by zeroing every
+    /// entry, and return a pointer to it for [RawQueue::new]. The buffer should be as long as the
+    /// queue. The consumer checks the turn bits of entries that haven't been written yet, so
+    /// pairing a new (or [RawQueueHdr::reset]) header with an uninitialized buffer would make it
+    /// read garbage, and possibly take a slot that was never submitted as a ready entry.
+    pub fn init_buffer(buf: &mut [MaybeUninit<QueueEntry<T>>]) -> *mut QueueEntry<T> {
+        for slot in buf.iter_mut() {
+            *slot = MaybeUninit::zeroed();
+        }
+        buf.as_mut_ptr().cast()
+    }
+
+    // Enter the consumer's critical section, which lasts until the returned guard is dropped. A
+    // reentrant receive would corrupt the tail, so with debug-checks we panic, and otherwise we
+    // refuse to continue.
+    #[inline]
+    fn enter_consumer(&self) -> Result<ConsumerGuard<'_>, QueueError> {
+        ConsumerGuard::enter(&self.receiving)
+    }
+
+    // Check the ready entry at position t before it's handed out or looked at. With debug-checks,
+    // this refuses an entry that wasn't submitted at that position, and with the checksum feature,
+    // one whose contents changed after it was published. Every path that returns an entry, or
+    // passes one to the caller, goes through here.
+    #[inline]
+    fn check_entry(&self, t: CounterValue, item: &QueueEntry<T>) -> Result<(), QueueError> {
+        #[cfg(feature = "debug-checks")]
+        self.hdr().check_slot(t, item)?;
+        #[cfg(feature = "checksum")]
+        item.verify()?;
+        #[cfg(not(feature = "debug-checks"))]
+        let _ = t;
+        #[cfg(not(any(feature = "debug-checks", feature = "checksum")))]
+        let _ = item;
+        Ok(())
+    }
+
+    /// Submit a data item of type T, wrapped in a QueueEntry, to the queue. The two callbacks,
+    /// wait, and ring, are for implementing a rudimentary condvar, wherein if the queue needs to
+    /// block, we'll call wait(x, y), where we are supposed to wait until *x != y. Once we are done
+    /// inserting, if we need to wake up a consumer, we will call ring, which should wake up anyone
+    /// waiting on that word of memory.
+    ///
+    /// Submitters are served in the order that they arrive. Each one claims its slot up front by
+    /// bumping the head, like taking a ticket, and then waits until the consumer has freed that
+    /// particular slot, so a submitter that arrives later, or keeps retrying, can't take a slot
+    /// from one that is already waiting. (The non-blocking and timed submissions only claim a
+    /// slot once there's room, so they can't overtake a waiting submitter either.) The cost is
+    /// that the consumer can't wake just the next submitter in line: each ring of the tail wakes
+    /// every waiting submitter, and those whose slots are still taken go back to waiting.
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.submit_with_idle(item, wait, ring, core::hint::spin_loop, flags)
+    }
+
+    /// Submit a data item, like [RawQueue::submit], but with a power-aware wait. If the queue is
+    /// full, then after a short spin the submitter calls `idle` instead of busy-spinning. This
+    /// should be something that lets the core drop into a low-power state until something
+    /// happens, for example a halt or wait-for-event instruction. Once the idle budget is used
+    /// up, the submitter falls back to calling wait as normal.
+    pub fn submit_with_idle<W: Fn(&Counter, CounterValue), R: Fn(&Counter), I: Fn()>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        idle: I,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        let mut backoff = SpinThenPark::with_idle(self.hdr().spin_limit(), idle);
+        self.submit_with_backoff(item, wait, ring, &mut backoff, flags)
+    }
+
+    /// Submit a data item, like [RawQueue::submit], but spin with the given backoff strategy
+    /// while the queue is full, instead of the queue's own spin limit. Once the backoff says to
+    /// stop spinning, the submitter falls back to calling wait as normal.
+    pub fn submit_with_backoff<W: Fn(&Counter, CounterValue), R: Fn(&Counter), B: Backoff>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        backoff: &mut B,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        let h = self.hdr().reserve_slots(1, flags, wait, backoff)?;
+        let buf_item = self.get_buf(h as usize);
+        // The slot's previous contents were moved out by the consumer, so don't drop them.
+        unsafe { item.write_into(buf_item) };
+        buf_item.publish(self.hdr().cmd_slot(h, false));
+
+        self.hdr().ring(ring);
+        Ok(())
+    }
+
+    /// Submit a data item, like [RawQueue::submit], and return a ticket saying where in the queue
+    /// it landed. The receiver can match the ticket to the entry with [QueueEntry::position], for
+    /// correlating entries by position when their info tags aren't unique.
+    pub fn submit_tracked<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<SubmitTicket, QueueError> {
+        let h = self
+            .hdr()
+            .reserve_slots(1, flags, wait, &mut self.hdr().backoff())?;
+        let buf_item = self.get_buf(h as usize);
+        unsafe { item.write_into(buf_item) };
+        buf_item.publish(self.hdr().cmd_slot(h, false));
+
+        self.hdr().ring(ring);
+        Ok(SubmitTicket {
+            position: h,
+            index: h as usize & (self.hdr().len() - 1),
+        })
+    }
+
+    /// Submit a data item, like [RawQueue::submit], and report whether the submission had to wake
+    /// the consumer. This exposes the decision that submit makes internally about calling ring,
+    /// which is handy for seeing how often submissions cost a wake (and so likely a syscall).
+    pub fn submit_notify<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<Notified, QueueError> {
+        let h = self
+            .hdr()
+            .reserve_slots(1, flags, wait, &mut self.hdr().backoff())?;
+        let buf_item = self.get_buf(h as usize);
+        unsafe { item.write_into(buf_item) };
+        buf_item.publish(self.hdr().cmd_slot(h, false));
+
+        if self.hdr().ring(ring) {
+            Ok(Notified::Rang)
+        } else {
+            Ok(Notified::Skipped)
+        }
+    }
+
+    /// Submit a data item, like [RawQueue::submit], unless the last entry submitted to the queue
+    /// has the same info tag and is still waiting to be received, in which case the item is
+    /// dropped and this returns Ok([Submitted::Coalesced]). This is for redundant notifications,
+    /// such as "data ready" doorbells, where one pending entry is as good as several.
+    ///
+    /// Only the single entry immediately before the head is compared, and only if it has been
+    /// published. If another submitter has reserved that slot but not yet published it, or the
+    /// matching entry is further back in the queue, the item is submitted as normal.
+    ///
+    /// The check races with the consumer, which may receive the entry before the head between the
+    /// check and the return. The item is then dropped even though nothing is pending any more, so
+    /// Ok([Submitted::Coalesced]) only means that a matching entry was pending at some point
+    /// during the call. That's still fine for notifications, since the consumer received the
+    /// matching entry after this call began. But callers mustn't assume that the matching entry
+    /// is still in the queue once this returns.
+    ///
+    /// As with submit, this returns Err([QueueError::Closed]) if the queue is closed, even if a
+    /// matching entry is still waiting to be received.
+    pub fn submit_dedup<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<Submitted, QueueError> {
+        if self.hdr().is_closed() {
+            return Err(QueueError::Closed);
+        }
+        if self.last_pending_info() == Some(item.info()) {
+            return Ok(Submitted::Coalesced);
+        }
+        self.submit(item, wait, ring, flags)?;
+        Ok(Submitted::Enqueued)
+    }
+
+    /// Submit a data item without ever waiting for room: if the queue is full, the oldest entry is
+    /// dropped to make room for the new one. This is for lossy streams, such as telemetry, where
+    /// stale entries aren't worth keeping and a producer mustn't block.
+    ///
+    /// Dropping the oldest entry means the producer moves the tail, which is normally only written
+    /// by the consumer. The producer claims the entry with a compare-and-swap on the tail, exactly
+    /// as [RawQueue::receive_mpmc] does, so the consumer must receive with receive_mpmc, and never
+    /// with [RawQueue::receive] or the other single-consumer functions, whose plain stores of the
+    /// tail would undo the producer's. Then every entry is either received or dropped, never both.
+    ///
+    /// The only time this waits is if the oldest entry has been reserved by another submitter that
+    /// hasn't published it yet, in which case it spins until it's published, since a partly
+    /// written entry can't be dropped. Returns Err([QueueError::Closed]) if the queue is closed.
+    pub fn submit_overwrite<R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        ring: R,
+    ) -> Result<(), QueueError>
+    where
+        T: Copy,
+    {
+        let hdr = self.hdr();
+        loop {
+            match hdr.try_reserve_slots(1, 1) {
+                Ok((h, _)) => {
+                    let buf_item = self.get_buf(h as usize);
+                    buf_item.info = item.info;
+                    buf_item.data = item.data;
+                    buf_item.publish(hdr.cmd_slot(h, false));
+                    hdr.ring(ring);
+                    return Ok(());
+                }
+                Err(QueueError::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+
+            // The queue is full, so drop the entry at the tail, once it's been published.
+            let raw = hdr.tail.load(Ordering::SeqCst);
+            let t = raw & COUNTER_MASK;
+            if !hdr.is_turn(t, self.get_buf(t as usize)) {
+                core::hint::spin_loop();
+                continue;
+            }
+            let next = (t.wrapping_add(1) & COUNTER_MASK) | (raw & CONSUMER_WAITING);
+            if hdr
+                .tail
+                .compare_exchange(raw, next, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+                && hdr.submitter_waiting()
+            {
+                ring(&hdr.tail);
+            }
+        }
+    }
+
+    // The info tag of the entry immediately before the head, if it has been published and not yet
+    // received. The slot may be reused while it's being read, so its cmd_slot is checked both
+    // before and after reading the info, as with a seqlock: the cmd_slot includes the position the
+    // entry was submitted at, so it only stays the same if the slot held the same entry
+    // throughout.
+    #[allow(clippy::unnecessary_cast)] // not unnecessary without narrow-counters
+    fn last_pending_info(&self) -> Option<Info> {
+        let hdr = self.hdr();
+        let h = hdr.head.load(Ordering::SeqCst) & POSITION_MASK;
+        let t = hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        if hdr.is_empty(h as CounterValue, t) {
+            return None;
+        }
+        let last = h.wrapping_sub(1) & POSITION_MASK;
+        let expected = hdr.cmd_slot(last, false);
+        let slot = self.get_buf(last as usize) as *const QueueEntry<T>;
+        let entry = unsafe { &*slot };
+        if entry.get_cmd_slot() != expected {
+            return None;
+        }
+        let info = unsafe { core::ptr::addr_of!((*slot).info).read_volatile() };
+        if entry.get_cmd_slot() != expected {
+            return None;
+        }
+        Some(info)
+    }
+
+    /// Submit a data item, like [RawQueue::submit], but give up once a deadline has passed. The
+    /// deadline callback works as in [RawQueue::receive_timeout]. Returns
+    /// Err([QueueError::TimedOut]) if the queue is still full when the deadline passes.
+    ///
+    /// Unlike submit, which claims a slot up front and then waits for it to free up, this only
+    /// claims a slot once there's room for it. So if the submission times out, the queue is left
+    /// exactly as it was, with no slot claimed that the consumer would wait on.
+    pub fn submit_timeout<W: Fn(&Counter, CounterValue), R: Fn(&Counter), D: Fn() -> bool>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+        deadline: D,
+    ) -> Result<(), QueueError> {
+        let h = self.hdr().reserve_slot_until(flags, wait, deadline)?;
+        let buf_item = self.get_buf(h as usize);
+        unsafe { item.write_into(buf_item) };
+        buf_item.publish(self.hdr().cmd_slot(h, false));
+
+        self.hdr().ring(ring);
+        Ok(())
+    }
+
+    // Submit a data item if there's room for it right now, without claiming a slot otherwise.
+    fn try_submit<R: Fn(&Counter)>(&self, item: QueueEntry<T>, ring: R) -> Result<(), QueueError> {
+        let (h, _) = self.hdr().try_reserve_slots(1, 1)?;
+        let buf_item = self.get_buf(h as usize);
+        unsafe { item.write_into(buf_item) };
+        buf_item.publish(self.hdr().cmd_slot(h, false));
+
+        self.hdr().ring(ring);
+        Ok(())
+    }
+
+    /// Submit an entry by filling it out directly in its slot, rather than building it first and
+    /// then copying it in, which saves a copy for large data types. Once a slot is reserved, its
+    /// info tag is set to `info`, and `fill` is called with a reference to the entry to fill out
+    /// the data. The entry is published once `fill` returns. If `fill` panics, the slot is marked
+    /// as abandoned so that the consumer skips it. The data starts out with whatever was left in
+    /// the slot, so `fill` should overwrite all of it. Otherwise, this works like
+    /// [RawQueue::submit].
+    pub fn submit_in_place<
+        F: FnOnce(&mut QueueEntry<T>),
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+    >(
+        &self,
+        fill: F,
+        info: Info,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError>
+    where
+        T: Copy,
+    {
+        let mut range = self.reserve_contiguous(1, wait, ring, flags)?;
+        let entry = range.entry_mut(0);
+        entry.info = info;
+        fill(entry);
+        range.publish();
+        Ok(())
+    }
+
+    /// Submit a data item on behalf of a particular producer, tagging the entry with the producer
+    /// id so that the consumer can attribute it via [QueueEntry::producer_id]. The info tag of
+    /// the item must fit within [PRODUCER_INFO_MASK]. Otherwise, this works like
+    /// [RawQueue::submit].
+    pub fn submit_as<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        producer_id: u8,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.submit(item.with_producer_id(producer_id), wait, ring, flags)
+    }
+
+    /// Submit a request and block until its completion arrives on a paired response queue, for
+    /// RPC-style synchronous calls. The info tag of the request is used as its correlation id,
+    /// so the caller must pick an id that isn't used by any other outstanding request. Once the
+    /// request is submitted, we receive from `response` until a completion with the same info tag
+    /// arrives, and return it.
+    ///
+    /// Completions may arrive out of order, so any completion that doesn't match is passed to
+    /// `dispatch` instead of being dropped. Since this receives from `response`, the caller
+    /// becomes that queue's consumer for the duration of the call, so only a single caller may be
+    /// waiting on a given response queue at a time, and nobody else may be receiving from it.
+    pub fn submit_and_wait<
+        U: EntryData,
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+        D: FnMut(QueueEntry<U>),
+    >(
+        &self,
+        request: QueueEntry<T>,
+        response: &RawQueue<U>,
+        wait: W,
+        ring: R,
+        mut dispatch: D,
+    ) -> Result<QueueEntry<U>, QueueError> {
+        let id = request.info();
+        self.submit(request, &wait, &ring, SubmissionFlags::empty())?;
+        loop {
+            let completion = response.receive(&wait, &ring, ReceiveFlags::empty())?;
+            if completion.info() == id {
+                return Ok(completion);
+            }
+            dispatch(completion);
+        }
+    }
+
+    /// Submit entries from an iterator, one at a time and in order, until the iterator runs out or
+    /// a submission fails. Returns the number of entries submitted, or on failure, the number
+    /// submitted before the failure along with the error. The entry that failed is dropped, so to
+    /// retry, resume the source from that count. With NON_BLOCK, this stops at the first entry
+    /// that doesn't fit, with Err([QueueError::WouldBlock]). The wait and ring callbacks work as
+    /// in [RawQueue::submit].
+    pub fn submit_all<
+        I: IntoIterator<Item = QueueEntry<T>>,
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+    >(
+        &self,
+        items: I,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<usize, (usize, QueueError)> {
+        let mut count = 0;
+        for item in items {
+            let res = if flags.contains(SubmissionFlags::NON_BLOCK) {
+                self.try_submit(item, &ring)
+            } else {
+                self.submit(item, &wait, &ring, flags)
+            };
+            res.map_err(|e| (count, e))?;
+            count += 1;
         }
+        Ok(count)
+    }
+
+    /// Submit a batch of entries to the queue, in order. Each run of entries is reserved with a
+    /// single update of the head, and the doorbell is rung once per run instead of once per entry.
+    /// Returns the number of entries submitted.
+    ///
+    /// A batch no longer than the queue is submitted as a single run. Longer batches are split
+    /// into runs of the queue's length, each of which has to wait for the consumer to make room.
+    /// With NON_BLOCK, this submits as many entries as currently fit, and returns
+    /// Err([QueueError::WouldBlock]) only if none do. The wait and ring callbacks work as in
+    /// [RawQueue::submit].
+    pub fn submit_batch<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        items: &[QueueEntry<T>],
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<usize, QueueError>
+    where
+        T: Copy,
+    {
+        let mut count = 0;
+        for chunk in items.chunks(self.hdr().len()) {
+            let n = chunk.len() as u32;
+            let (start, len) = if flags.contains(SubmissionFlags::NON_BLOCK) {
+                match self.hdr().try_reserve_slots(1, n) {
+                    Ok(reserved) => reserved,
+                    Err(QueueError::WouldBlock) if count > 0 => break,
+                    Err(e) => return Err(e),
+                }
+            } else {
+                let start = self
+                    .hdr()
+                    .reserve_slots(n, flags, &wait, &mut self.hdr().backoff())?;
+                (start, n)
+            };
+            let mut range = SlotRange {
+                queue: self,
+                start,
+                len,
+                ring: &ring,
+                published: false,
+            };
+            for (i, item) in chunk[..len as usize].iter().enumerate() {
+                // Leave the cmd_slot alone until the range is published.
+                let entry = range.entry_mut(i);
+                entry.info = item.info;
+                entry.data = item.data;
+            }
+            range.publish();
+            count += len as usize;
+            if len < n {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Reserve a run of `n` consecutive slots in the queue, for callers that want to fill out the
+    /// entries themselves before publishing them. The returned [SlotRange] gives access to each
+    /// reserved entry, and [SlotRange::publish] makes them all visible to the consumer at once,
+    /// ringing the doorbell a single time. If the range is dropped without being published, the
+    /// slots are marked as abandoned and the consumer skips over them. The entries may be filled
+    /// out in any order, and even from several threads at once (see [SlotRange::as_mut_slices]).
+    ///
+    /// The run is consecutive in terms of queue positions, but may wrap around the end of the
+    /// buffer. Reserving more than the queue's length returns Err([QueueError::Unknown]). The wait
+    /// callback works as in [RawQueue::submit], and the ring callback is held by the range until
+    /// it's published or dropped.
+    pub fn reserve_contiguous<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        n: usize,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<SlotRange<'_, T, R>, QueueError>
+    where
+        T: Copy,
+    {
+        let n = u32::try_from(n).map_err(|_| QueueError::Unknown)?;
+        let start = self
+            .hdr()
+            .reserve_slots(n, flags, wait, &mut self.hdr().backoff())?;
+        Ok(SlotRange {
+            queue: self,
+            start,
+            len: n,
+            ring,
+            published: false,
+        })
+    }
+
+    /// Receive data from the queue, returning either that data or an error. The wait and ring
+    /// callbacks work similar to [RawQueue::submit].
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        self.receive_with_idle(wait, ring, core::hint::spin_loop, flags)
+    }
+
+    /// Receive data from the queue if any is ready, without blocking. Returns Ok(None) if the
+    /// queue is empty, which is often an expected case for a polling consumer rather than an
+    /// error. Other errors are still returned. The ring callback works as in
+    /// [RawQueue::receive].
+    pub fn try_receive<R: Fn(&Counter)>(
+        &self,
+        ring: R,
+    ) -> Result<Option<QueueEntry<T>>, QueueError> {
+        // Non-blocking receives never call the wait callback.
+        match self.receive(|_, _| {}, ring, ReceiveFlags::NON_BLOCK) {
+            Ok(item) => Ok(Some(item)),
+            Err(QueueError::WouldBlock) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but with a power-aware wait. See
+    /// [RawQueue::submit_with_idle].
+    pub fn receive_with_idle<W: Fn(&Counter, CounterValue), R: Fn(&Counter), I: Fn()>(
+        &self,
+        wait: W,
+        ring: R,
+        idle: I,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        let mut backoff = SpinThenPark::with_idle(self.hdr().spin_limit(), idle);
+        self.receive_inner(wait, ring, &mut backoff, || false, flags)
+            .map(|(_, item)| item)
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but spin with the given backoff
+    /// strategy while the queue is empty. See [RawQueue::submit_with_backoff].
+    pub fn receive_with_backoff<W: Fn(&Counter, CounterValue), R: Fn(&Counter), B: Backoff>(
+        &self,
+        wait: W,
+        ring: R,
+        backoff: &mut B,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        self.receive_inner(wait, ring, backoff, || false, flags)
+            .map(|(_, item)| item)
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but give up once a deadline has
+    /// passed. The deadline callback should return true once the deadline has passed, and is
+    /// checked each time the consumer would otherwise call wait, so the wait callback itself
+    /// should return periodically (for example, a futex wait with a timeout) for the deadline to
+    /// be noticed promptly. Returns Err([QueueError::TimedOut]) if the queue is still empty when
+    /// the deadline passes.
+    pub fn receive_timeout<W: Fn(&Counter, CounterValue), R: Fn(&Counter), D: Fn() -> bool>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+        deadline: D,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        self.receive_inner(wait, ring, &mut self.hdr().backoff(), deadline, flags)
+            .map(|(_, item)| item)
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but also return the entry's
+    /// sequence number: the value of the tail counter when it was received. Sequence numbers go up
+    /// by exactly one for each slot the consumer moves past (and wrap with the counter), so a
+    /// consumer can check that it hasn't skipped or repeated any entries. Abandoned entries are
+    /// skipped, and so leave a gap in the sequence numbers the consumer sees.
+    pub fn receive_seq<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<(CounterValue, QueueEntry<T>), QueueError> {
+        self.receive_inner(wait, ring, &mut self.hdr().backoff(), || false, flags)
+    }
+
+    /// Receive the next entry from the queue, but only if it satisfies a predicate. This waits for
+    /// an entry as in [RawQueue::receive] (unless NON_BLOCK is specified), and then calls `pred` on
+    /// it while it's still in the queue. If `pred` returns true, the entry is received and
+    /// returned. Otherwise it's left in place as the next entry to be received, and this returns
+    /// Ok(None). Only the consumer moves the tail, so nobody else can take the entry in between.
+    /// Abandoned entries are skipped without being passed to `pred`.
+    pub fn receive_if<
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+        P: FnOnce(&QueueEntry<T>) -> bool,
+    >(
+        &self,
+        pred: P,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<Option<QueueEntry<T>>, QueueError> {
+        let _guard = self.enter_consumer()?;
+        let mut backoff = self.hdr().backoff();
+        let slot = loop {
+            let t = self
+                .hdr()
+                .get_next_ready(&wait, &mut backoff, || false, flags, self.buf)?;
+            let slot = self.get_buf(t as usize);
+            self.check_entry(t, slot)?;
+            if !slot.is_abandoned() {
+                break slot;
+            }
+            self.hdr().advance_tail(&ring);
+        };
+        if !pred(slot) {
+            return Ok(None);
+        }
+        let item = unsafe { QueueEntry::read_from(slot) };
+        self.hdr().advance_tail(&ring);
+        Ok(Some(item))
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but also return how many more
+    /// entries were ready right after this one was taken, so a consumer can decide whether to keep
+    /// draining or to yield without a separate check that races with producers. The count is only
+    /// a snapshot: producers may submit more at any time, and it includes any abandoned entries
+    /// that the next receive will skip.
+    pub fn receive_with_remaining<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<(QueueEntry<T>, usize), QueueError> {
+        let (t, item) = self.receive_seq(wait, ring, flags)?;
+        let b = self.hdr().bell.load(Ordering::SeqCst);
+        let remaining = b.wrapping_sub(t.wrapping_add(1)) & COUNTER_MASK;
+        Ok((item, remaining as usize))
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], but move the entry straight from its
+    /// slot into `dst` instead of returning it. For large entries, this lets the caller place the
+    /// entry where it's going to live (for example, in a preallocated slab) without an extra copy
+    /// through the return value. On success, `dst` is initialized and owns the entry. On error,
+    /// `dst` is left as it was.
+    pub fn receive_into<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        dst: &mut MaybeUninit<QueueEntry<T>>,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<(), QueueError> {
+        let _guard = self.enter_consumer()?;
+        let mut backoff = self.hdr().backoff();
+        loop {
+            let t = self
+                .hdr()
+                .get_next_ready(&wait, &mut backoff, || false, flags, self.buf)?;
+            let item = self.get_buf(t as usize);
+            self.check_entry(t, item)?;
+            let abandoned = item.is_abandoned();
+            if !abandoned {
+                unsafe { dst.write(QueueEntry::read_from(item)) };
+            }
+            self.hdr().advance_tail(&ring);
+            if !abandoned {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive data from the queue without copying it out, returning a guard that borrows the
+    /// entry in place. The entry stays in its slot, and the tail isn't advanced (so submitters
+    /// can't reuse the slot), until the guard is dropped, at which point any waiting submitters are
+    /// woken with the ring callback. Otherwise, this works like [RawQueue::receive].
+    ///
+    /// The guard counts as a receive in progress, so trying to receive again from the same queue
+    /// while it's alive is treated as a reentrant receive (see [QueueError::Reentrant]).
+    ///
+    /// This is also the way to push an entry back onto the front of the queue, if the consumer
+    /// finds it can't handle it yet: see [QueueGuard::unreceive].
+    pub fn receive_ref<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueGuard<'_, T, R>, QueueError> {
+        let consumer = self.enter_consumer()?;
+        loop {
+            let t = self.hdr().get_next_ready(
+                &wait,
+                &mut self.hdr().backoff(),
+                || false,
+                flags,
+                self.buf,
+            )?;
+            let item = self.get_buf(t as usize);
+            self.check_entry(t, item)?;
+            if !item.is_abandoned() {
+                return Ok(QueueGuard {
+                    queue: self,
+                    pos: t,
+                    ring,
+                    consumed: true,
+                    _consumer: consumer,
+                });
+            }
+            self.hdr().advance_tail(&ring);
+        }
+    }
+
+    fn receive_inner<
+        W: Fn(&Counter, CounterValue),
+        R: Fn(&Counter),
+        B: Backoff,
+        D: Fn() -> bool,
+    >(
+        &self,
+        wait: W,
+        ring: R,
+        backoff: &mut B,
+        deadline: D,
+        flags: ReceiveFlags,
+    ) -> Result<(CounterValue, QueueEntry<T>), QueueError> {
+        let _guard = self.enter_consumer()?;
+        loop {
+            let t = self
+                .hdr()
+                .get_next_ready(&wait, &mut *backoff, &deadline, flags, self.buf)?;
+            // Move the entry out of the slot, but only take ownership of it if it's one we're
+            // returning. Abandoned and stale slots don't hold a valid payload.
+            let item =
+                ManuallyDrop::new(unsafe { QueueEntry::read_from(self.get_buf(t as usize)) });
+            self.check_entry(t, &item)?;
+            self.hdr().advance_tail(&ring);
+            if !item.is_abandoned() {
+                return Ok((t, ManuallyDrop::into_inner(item)));
+            }
+        }
+    }
+
+    /// Receive data from a queue that has multiple consumers, which must all receive with this
+    /// function (and never with [RawQueue::receive] or the other single-consumer functions). Each
+    /// consumer claims the entry at the tail by advancing the tail with a compare-and-swap, so
+    /// every entry is received by exactly one consumer. Otherwise, this works like
+    /// [RawQueue::receive].
+    ///
+    /// Since several consumers may be waiting at once, the ring callback that producers use must
+    /// wake all the threads waiting on the bell, not just one. And since no one consumer can know
+    /// that the others are done waiting, the consumer's waiting flag is left set once any consumer
+    /// has waited, so producers ring on every submission afterwards (unless wakes are coalesced,
+    /// see [QueueMode]).
+    pub fn receive_mpmc<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError>
+    where
+        T: Copy,
+    {
+        let hdr = self.hdr();
+        let limit = hdr.spin_limit();
+        let mut attempts = limit;
+        loop {
+            let raw = hdr.tail.load(Ordering::SeqCst);
+            let t = raw & COUNTER_MASK;
+            let b = hdr.bell.load(Ordering::SeqCst);
+            let slot = self.get_buf(t as usize) as *const QueueEntry<T>;
+            if !hdr.is_empty(b, t) && hdr.is_turn(t, slot) {
+                // Copy the entry out before claiming it, since once the tail moves on a producer
+                // may overwrite the slot. If another consumer claims it first, the copy may be
+                // torn, but then the compare-and-swap fails and the copy is thrown away.
+                let item = unsafe { QueueEntry::read_from(slot) };
+                // A copy that fails the check is only corrupt if nobody claimed the entry while we
+                // were reading it, in which case it's left at the front of the queue.
+                if let Err(e) = self.check_entry(t, &item) {
+                    if hdr.tail.load(Ordering::SeqCst) == raw {
+                        return Err(e);
+                    }
+                    continue;
+                }
+                let next = (t.wrapping_add(1) & COUNTER_MASK) | (raw & CONSUMER_WAITING);
+                if hdr
+                    .tail
+                    .compare_exchange(raw, next, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    continue;
+                }
+                if hdr.submitter_waiting() {
+                    ring(&hdr.tail);
+                }
+                if item.is_abandoned() {
+                    continue;
+                }
+                return Ok(item);
+            }
+
+            if hdr.is_closed() {
+                return Err(QueueError::Closed);
+            }
+
+            if flags.contains(ReceiveFlags::NON_BLOCK) {
+                return Err(QueueError::WouldBlock);
+            }
+
+            if attempts != 0 {
+                attempts -= 1;
+                relax(limit, attempts, &core::hint::spin_loop);
+                continue;
+            }
+
+            hdr.consumer_set_waiting(true);
+            let b = hdr.bell.load(Ordering::SeqCst);
+            if hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK == t
+                && (hdr.is_empty(b, t) || !hdr.is_turn(t, slot))
+            {
+                hdr.wait_bell(&wait, b);
+            }
+        }
+    }
+
+    /// Receive up to `max` entries from the queue without blocking, passing each one to `f` in
+    /// order. Returns the number of entries received, which may be zero. Like receive, only the
+    /// single consumer may call this.
+    ///
+    /// Compared to calling receive in a loop, this keeps the tail locally for the whole run (the
+    /// consumer is its only writer, so nobody else can change it), and only writes it back and
+    /// wakes waiting submitters once at the end. This saves several atomic operations per entry
+    /// when draining a long run of entries.
+    ///
+    /// A corrupt entry (see [QueueError::Corrupt]) stops the run, and is left at the front of the
+    /// queue. If no entries were received before it, this returns the error.
+    pub fn receive_many<R: Fn(&Counter), F: FnMut(QueueEntry<T>)>(
+        &self,
+        max: usize,
+        ring: R,
+        mut f: F,
+    ) -> Result<usize, QueueError>
+    where
+        T: Copy,
+    {
+        let _guard = self.enter_consumer()?;
+        let mut cursor = DrainCursor::new(self.hdr(), ring);
+        let mut count = 0;
+        while count < max {
+            let Some(t) = cursor.next_ready(self.buf) else {
+                break;
+            };
+            let item = unsafe { QueueEntry::read_from(self.get_buf(t as usize)) };
+            if let Err(e) = self.check_entry(t, &item) {
+                if count == 0 {
+                    return Err(e);
+                }
+                break;
+            }
+            cursor.advance();
+            if !item.is_abandoned() {
+                f(item);
+                count += 1;
+            }
+        }
+        drop(cursor);
+        Ok(count)
+    }
+
+    /// Receive a batch of entries from the queue into `out`, returning the number received. If
+    /// the queue is empty, this waits (unless NON_BLOCK is specified) until at least one entry is
+    /// ready, and then takes every ready entry, up to the length of `out`, without waiting again.
+    /// Like [RawQueue::receive_many], the tail is written back, and waiting submitters are woken,
+    /// just once for the whole batch. As in [RawQueue::receive_many], a corrupt entry ends the
+    /// batch, and is only reported if it's the first entry.
+    pub fn receive_batch<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        out: &mut [QueueEntry<T>],
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<usize, QueueError>
+    where
+        T: Copy,
+    {
+        let _guard = self.enter_consumer()?;
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        // If every ready entry turns out to be abandoned, we have to go back to waiting.
+        while count == 0 {
+            self.hdr().get_next_ready(
+                &wait,
+                &mut self.hdr().backoff(),
+                || false,
+                flags,
+                self.buf,
+            )?;
+            let mut cursor = DrainCursor::new(self.hdr(), &ring);
+            while count < out.len() {
+                let Some(t) = cursor.next_ready(self.buf) else {
+                    break;
+                };
+                let item = unsafe { QueueEntry::read_from(self.get_buf(t as usize)) };
+                if let Err(e) = self.check_entry(t, &item) {
+                    if count == 0 {
+                        return Err(e);
+                    }
+                    break;
+                }
+                cursor.advance();
+                if !item.is_abandoned() {
+                    out[count] = item;
+                    count += 1;
+                }
+            }
+            drop(cursor);
+        }
+        Ok(count)
+    }
+
+    /// Look at the entry `n` positions past the next entry to be received, without consuming
+    /// anything. A lookahead of 0 inspects the next entry that [RawQueue::receive] would return.
+    /// This never blocks, returning Err([QueueError::WouldBlock]) if the entry at that position
+    /// hasn't been published yet. It's useful for making coalescing or reordering decisions before
+    /// committing to receiving entries.
+    ///
+    /// Note that this is a racy snapshot --- producers may publish more entries at any time, and
+    /// the entries between the tail and position `n` may not have been published yet even if the
+    /// entry at `n` has been.
+    pub fn peek_ahead(&self, n: usize) -> Result<QueueEntry<T>, QueueError>
+    where
+        T: Copy,
+    {
+        let t = self.hdr().get_ready_ahead(n, self.buf)?;
+        let item = unsafe { QueueEntry::read_from(self.get_buf(t as usize)) };
+        self.check_entry(t, &item)?;
+        Ok(item)
+    }
+
+    /// Look at the next entry that [RawQueue::receive] would return, without consuming it. This
+    /// never blocks, and doesn't mark the consumer as waiting, returning None if no entry is
+    /// ready, or if the entry is corrupt. Like [RawQueue::peek_ahead], this is a racy snapshot.
+    pub fn peek(&self) -> Option<QueueEntry<T>>
+    where
+        T: Copy,
+    {
+        (0..self.hdr().len())
+            .map_while(|n| self.peek_ahead(n).ok())
+            .find(|item| !item.is_abandoned())
+    }
+
+    /// Read the info tags of the ready entries, in the order they'd be received, into `out`,
+    /// without consuming anything, and return how many were written. The scan goes from the tail
+    /// up to the first entry that hasn't been published yet, skipping abandoned entries, and stops
+    /// early if `out` fills up. This lets a consumer look at more than the next entry before
+    /// deciding how to handle them (for example, to coalesce work with the same tag).
+    ///
+    /// Like [RawQueue::peek_ahead], this is a racy snapshot: more entries may be published right
+    /// after it returns. The entries are still in the queue, and must be received as usual.
+    pub fn peek_infos(&self, out: &mut [Info]) -> usize {
+        let hdr = self.hdr();
+        let mut count = 0;
+        for n in 0..hdr.len() {
+            if count == out.len() {
+                break;
+            }
+            let Ok(t) = hdr.get_ready_ahead(n, self.buf) else {
+                break;
+            };
+            let item = self.get_buf(t as usize);
+            if !item.is_abandoned() {
+                out[count] = item.info();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Receive every entry that's in the queue right now, without blocking. The returned iterator
+    /// takes a snapshot of the bell when it's created, and yields ready entries until it reaches
+    /// that point, so it finishes even if producers keep submitting. It also stops early at an
+    /// entry that's been reserved but not yet published, or at a corrupt one (see
+    /// [QueueError::Corrupt]). Each entry is received as it's yielded, waking waiting submitters
+    /// with `ring` as usual. Like receive, only the single consumer may call this.
+    pub fn drain<R: Fn(&Counter)>(&self, ring: R) -> Drain<'_, T, R> {
+        Drain {
+            queue: self,
+            ring,
+            end: self.hdr().bell.load(Ordering::SeqCst) & COUNTER_MASK,
+            done: false,
+        }
+    }
+
+    /// Receive entries from the front of the queue for as long as they match a predicate, without
+    /// blocking. The returned iterator consumes and yields ready entries until it reaches one for
+    /// which `pred` returns false, or until the queue is empty (or the next entry is corrupt). The
+    /// non-matching entry is left in place as the next entry to be received.
+    ///
+    /// Since the queue is FIFO, this is not a general filter: it only ever drains a prefix of the
+    /// queue, and never skips over an entry to reach a later one. Like receive, only the single
+    /// consumer may call this.
+    pub fn drain_filter<R: Fn(&Counter), P: FnMut(&QueueEntry<T>) -> bool>(
+        &self,
+        ring: R,
+        pred: P,
+    ) -> DrainFilter<'_, T, R, P>
+    where
+        T: Copy,
+    {
+        DrainFilter {
+            queue: self,
+            ring,
+            pred,
+            done: false,
+        }
+    }
+
+    /// Receive entries as an iterator, for a consumer that loops forever handling entries. Each
+    /// call to next blocks in [RawQueue::receive] until an entry arrives, using the wait and ring
+    /// callbacks as usual. The iterator ends once the queue is closed (see [RawQueueHdr::close])
+    /// and every entry published before that has been received, or if a receive fails for any
+    /// other reason.
+    pub fn iter<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+    ) -> Iter<'_, T, W, R> {
+        Iter {
+            queue: self,
+            wait,
+            ring,
+            done: false,
+        }
+    }
+
+    pub fn setup_sleep<'a>(
+        &'a self,
+        sleep: bool,
+        output: &mut Option<QueueEntry<T>>,
+        waiter: &mut (Option<&'a Counter>, CounterValue),
+        ringer: &mut Option<&'a Counter>,
+    ) -> Result<(), QueueError> {
+        let _guard = self.enter_consumer()?;
+        loop {
+            let t = self.hdr().setup_rec_sleep(sleep, self.buf, waiter)?;
+            // As in receive, only take ownership of entries we're handing out.
+            let item =
+                ManuallyDrop::new(unsafe { QueueEntry::read_from(self.get_buf(t as usize)) });
+            self.check_entry(t, &item)?;
+            self.hdr().advance_tail_setup(ringer);
+            if !item.is_abandoned() {
+                *output = Some(ManuallyDrop::into_inner(item));
+                return Ok(());
+            }
+        }
+    }
+
+    /// Announce that a consumer is attached to this queue, so that producers can tell that someone
+    /// is listening via [RawQueue::is_consumer_present].
+    pub fn consumer_attach(&self) {
+        self.hdr().set_flag(HDR_FLAG_CONSUMER_PRESENT, true);
+    }
+
+    /// Announce that the consumer has detached from this queue.
+    pub fn consumer_detach(&self) {
+        self.hdr().set_flag(HDR_FLAG_CONSUMER_PRESENT, false);
+    }
+
+    /// Returns true if a consumer has attached to this queue and not yet detached. A producer can
+    /// check this before submitting to avoid filling up a queue that nobody is draining. Note that
+    /// this only says whether anyone is listening, not whether the consumer is making progress.
+    pub fn is_consumer_present(&self) -> bool {
+        self.hdr().has_flag(HDR_FLAG_CONSUMER_PRESENT)
+    }
+
+    /// Set the queue's performance tunings to one of the bundles in [QueueMode]. The tunings are
+    /// stored in the header, so they apply to every producer and the consumer of this queue.
+    pub fn set_mode(&self, mode: QueueMode) {
+        let hdr = self.hdr();
+        hdr.spin_limit.store(mode.spin_limit(), Ordering::Relaxed);
+        hdr.set_flag(HDR_FLAG_COALESCE_WAKES, mode.coalesce_wakes());
+    }
+
+    /// Get the mode the queue's tunings currently match, or None if they don't match any mode (as
+    /// is the case for a freshly constructed queue).
+    pub fn mode(&self) -> Option<QueueMode> {
+        let hdr = self.hdr();
+        [
+            QueueMode::LowLatency,
+            QueueMode::Throughput,
+            QueueMode::PowerSaving,
+        ]
+        .into_iter()
+        .find(|mode| {
+            hdr.spin_limit() == mode.spin_limit()
+                && hdr.has_flag(HDR_FLAG_COALESCE_WAKES) == mode.coalesce_wakes()
+        })
+    }
+
+    #[inline]
+    pub fn setup_sleep_simple(&self) -> (&Counter, CounterValue) {
+        self.hdr().setup_rec_sleep_simple()
+    }
+
+    #[inline]
+    pub fn setup_send_sleep_simple(&self) -> (&Counter, CounterValue) {
+        self.hdr().setup_send_sleep_simple()
+    }
+}
+
+/// A run of reserved slots in a queue that haven't been published yet. See
+/// [RawQueue::reserve_contiguous].
+pub struct SlotRange<'a, T: Copy + EntryData, R: Fn(&Counter)> {
+    queue: &'a RawQueue<T>,
+    start: u32,
+    len: u32,
+    ring: R,
+    published: bool,
+}
+
+impl<'a, T: Copy + EntryData, R: Fn(&Counter)> SlotRange<'a, T, R> {
+    /// The number of slots in this range.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns true if this range contains no slots.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get a mutable reference to the `i`th entry of this range, to fill it out before it's
+    /// published.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    pub fn entry_mut(&mut self, i: usize) -> &mut QueueEntry<T> {
+        assert!(i < self.len(), "slot index out of range");
+        self.queue
+            .get_buf(self.start.wrapping_add(i as u32) as usize)
+    }
+
+    /// Get a mutable reference to the data of the `i`th entry of this range.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    pub fn data_mut(&mut self, i: usize) -> &mut T {
+        &mut self.entry_mut(i).data
+    }
+
+    /// Get the entries of this range as two slices, in order, so that they can be filled out
+    /// separately (for example, by handing each one to a different worker). The range may wrap
+    /// around the end of the buffer, in which case the second slice holds the entries from the
+    /// start of the buffer. Otherwise, the second slice is empty.
+    ///
+    /// # Panics
+    /// Panics if the queue's entries are padded out to a larger stride (see
+    /// [RawQueueHdr::new_aligned]), since they can't then be viewed as a slice.
+    pub fn as_mut_slices(&mut self) -> (&mut [QueueEntry<T>], &mut [QueueEntry<T>]) {
+        assert_eq!(
+            self.queue.stride(),
+            core::mem::size_of::<QueueEntry<T>>(),
+            "entries are padded"
+        );
+        let cap = self.queue.hdr().len();
+        let first = self.start as usize & (cap - 1);
+        let len = self.len();
+        let head_len = len.min(cap - first);
+        unsafe {
+            (
+                core::slice::from_raw_parts_mut(self.queue.buf.add(first), head_len),
+                core::slice::from_raw_parts_mut(self.queue.buf, len - head_len),
+            )
+        }
+    }
+
+    /// The queue position of the `i`th slot of this range. The slot's index in the buffer is the
+    /// position modulo the queue's capacity.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    pub fn position(&self, i: usize) -> u32 {
+        assert!(i < self.len(), "slot index out of range");
+        self.start.wrapping_add(i as u32) & POSITION_MASK
+    }
+
+    /// The turn bit that the `i`th slot of this range will be published with, which alternates
+    /// each time the queue wraps around. This is set by [SlotRange::publish], so it's only
+    /// informational.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    pub fn turn(&self, i: usize) -> bool {
+        self.queue.hdr().get_turn(self.position(i))
+    }
+
+    /// Publish all the entries of this range to the consumer, in order, and ring the doorbell
+    /// once.
+    pub fn publish(mut self) {
+        self.finish(false);
+    }
+
+    fn finish(&mut self, abandoned: bool) {
+        let hdr = self.queue.hdr();
+        for i in 0..self.len {
+            let h = self.start.wrapping_add(i) & POSITION_MASK;
+            self.queue
+                .get_buf(h as usize)
+                .publish(hdr.cmd_slot(h, abandoned));
+        }
+        hdr.ring_many(self.len, &self.ring);
+        self.published = true;
+    }
+}
+
+impl<'a, T: Copy + EntryData, R: Fn(&Counter)> Drop for SlotRange<'a, T, R> {
+    fn drop(&mut self) {
+        if !self.published {
+            self.finish(true);
+        }
+    }
+}
+
+/// A received entry, borrowed in place from the queue's buffer. See [RawQueue::receive_ref].
+pub struct QueueGuard<'a, T, R: Fn(&Counter)> {
+    queue: &'a RawQueue<T>,
+    pos: CounterValue,
+    ring: R,
+    consumed: bool,
+    _consumer: ConsumerGuard<'a>,
+}
+
+impl<'a, T, R: Fn(&Counter)> QueueGuard<'a, T, R> {
+    /// Leave the entry at the front of the queue, rather than consuming it, so that the next
+    /// receive returns it again. This is for a consumer that finds it can't handle an entry yet,
+    /// and wants it to stay ahead of everything submitted after it, which resubmitting it would
+    /// not do.
+    ///
+    /// There's no way to do the same for an entry returned by [RawQueue::receive]: by then the
+    /// tail has moved past the entry's slot, and a submitter may already be filling it. With the
+    /// guard, the slot is never released, so pushing the entry back is always safe.
+    pub fn unreceive(mut self) {
+        self.consumed = false;
+    }
+}
+
+impl<'a, T, R: Fn(&Counter)> core::ops::Deref for QueueGuard<'a, T, R> {
+    type Target = QueueEntry<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.queue.get_buf(self.pos as usize)
+    }
+}
+
+impl<'a, T, R: Fn(&Counter)> Drop for QueueGuard<'a, T, R> {
+    fn drop(&mut self) {
+        if !self.consumed {
+            return;
+        }
+        // The entry was never moved out, so it's dropped here, before the slot is handed back.
+        unsafe { core::ptr::drop_in_place(self.queue.get_buf(self.pos as usize)) };
+        self.queue.hdr().advance_tail(&self.ring);
+    }
+}
+
+/// An iterator that receives the entries that were in a queue when it was created. See
+/// [RawQueue::drain].
+pub struct Drain<'a, T, R> {
+    queue: &'a RawQueue<T>,
+    ring: R,
+    end: CounterValue,
+    done: bool,
+}
+
+impl<'a, T: EntryData, R: Fn(&Counter)> Iterator for Drain<'a, T, R> {
+    type Item = QueueEntry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let hdr = self.queue.hdr();
+        let Ok(_guard) = self.queue.enter_consumer() else {
+            self.done = true;
+            return None;
+        };
+        loop {
+            if hdr.tail.load(ORD_OWN) & COUNTER_MASK == self.end {
+                self.done = true;
+                return None;
+            }
+            let Ok(t) = hdr.get_ready_ahead(0, self.queue.buf) else {
+                self.done = true;
+                return None;
+            };
+            let slot = self.queue.get_buf(t as usize);
+            // A corrupt entry ends the drain, and is left at the front of the queue.
+            if self.queue.check_entry(t, slot).is_err() {
+                self.done = true;
+                return None;
+            }
+            if slot.is_abandoned() {
+                hdr.advance_tail(&self.ring);
+                continue;
+            }
+            let item = unsafe { QueueEntry::read_from(slot) };
+            hdr.advance_tail(&self.ring);
+            return Some(item);
+        }
+    }
+}
+
+/// An iterator that receives entries from the front of a queue while they match a predicate. See
+/// [RawQueue::drain_filter].
+pub struct DrainFilter<'a, T, R, P> {
+    queue: &'a RawQueue<T>,
+    ring: R,
+    pred: P,
+    done: bool,
+}
+
+impl<'a, T: Copy + EntryData, R: Fn(&Counter), P: FnMut(&QueueEntry<T>) -> bool> Iterator
+    for DrainFilter<'a, T, R, P>
+{
+    type Item = QueueEntry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let hdr = self.queue.hdr();
+        let Ok(_guard) = self.queue.enter_consumer() else {
+            self.done = true;
+            return None;
+        };
+        loop {
+            let Ok(t) = hdr.get_ready_ahead(0, self.queue.buf) else {
+                self.done = true;
+                return None;
+            };
+            let item = unsafe { QueueEntry::read_from(self.queue.get_buf(t as usize)) };
+            if self.queue.check_entry(t, &item).is_err() {
+                self.done = true;
+                return None;
+            }
+            if item.is_abandoned() {
+                hdr.advance_tail(&self.ring);
+                continue;
+            }
+            if !(self.pred)(&item) {
+                self.done = true;
+                return None;
+            }
+            hdr.advance_tail(&self.ring);
+            return Some(item);
+        }
+    }
+}
+
+/// A blocking iterator over the entries received from a queue. See [RawQueue::iter].
+pub struct Iter<'a, T, W, R> {
+    queue: &'a RawQueue<T>,
+    wait: W,
+    ring: R,
+    done: bool,
+}
+
+impl<'a, T: EntryData, W: Fn(&Counter, CounterValue), R: Fn(&Counter)> Iterator
+    for Iter<'a, T, W, R>
+{
+    type Item = QueueEntry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self
+            .queue
+            .receive(&self.wait, &self.ring, ReceiveFlags::empty());
+        self.done = res.is_err();
+        res.ok()
+    }
+}
+
+unsafe impl<T: Send> Send for RawQueue<T> {}
+unsafe impl<T: Send> Sync for RawQueue<T> {}
+
+/// The wait and ring callbacks of a queue, bundled together into one object. Any pair of wait and
+/// ring closures, `(wait, ring)`, is a QueueWaiter.
+pub trait QueueWaiter {
+    /// Wait until *word != expected. See [RawQueue::submit].
+    fn wait(&self, word: &Counter, expected: CounterValue);
+    /// Wake anyone waiting on word.
+    fn wake(&self, word: &Counter);
+}
+
+/// A wait callback that spins until `*word != expected`, for contexts where there's nothing to
+/// block on, or blocking isn't allowed, such as early boot or an interrupt handler. Use it with
+/// [spin_wake]. Since nothing ever sleeps, a queue using this pair keeps its blocking semantics
+/// but burns CPU for as long as it waits.
+pub fn spin_wait(word: &Counter, expected: CounterValue) {
+    while word.load(Ordering::SeqCst) == expected {
+        core::hint::spin_loop();
+    }
+}
+
+/// A ring callback that does nothing, to pair with [spin_wait], whose waiters notice the change to
+/// the word without being woken.
+pub fn spin_wake(_word: &Counter) {}
+
+impl<W: Fn(&Counter, CounterValue), R: Fn(&Counter)> QueueWaiter for (W, R) {
+    fn wait(&self, word: &Counter, expected: CounterValue) {
+        (self.0)(word, expected)
+    }
+
+    fn wake(&self, word: &Counter) {
+        (self.1)(word)
+    }
+}
+
+/// A raw queue that holds onto its waiter, so that the wait and ring callbacks don't need to be
+/// passed to every call. See [RawQueue::with_waiter]. The rest of the [RawQueue] methods are
+/// available through deref.
+pub struct WaiterQueue<T, Q> {
+    queue: RawQueue<T>,
+    waiter: Q,
+}
+
+impl<T: EntryData> RawQueue<T> {
+    /// Construct a new raw queue out of a header reference and a buffer pointer, like
+    /// [RawQueue::new], bundled with a waiter to use for all its operations.
+    /// # Safety
+    /// See [RawQueue::new].
+    pub unsafe fn with_waiter<Q: QueueWaiter>(
+        hdr: *const RawQueueHdr,
+        buf: *mut QueueEntry<T>,
+        waiter: Q,
+    ) -> WaiterQueue<T, Q> {
+        WaiterQueue {
+            queue: Self::new(hdr, buf),
+            waiter,
+        }
+    }
+}
+
+impl<T: EntryData, Q: QueueWaiter> WaiterQueue<T, Q> {
+    /// Get the waiter used by this queue.
+    pub fn waiter(&self) -> &Q {
+        &self.waiter
+    }
+
+    /// Submit a data item to the queue. See [RawQueue::submit].
+    pub fn submit(&self, item: QueueEntry<T>, flags: SubmissionFlags) -> Result<(), QueueError> {
+        self.queue.submit(
+            item,
+            |w, v| self.waiter.wait(w, v),
+            |w| self.waiter.wake(w),
+            flags,
+        )
+    }
+
+    /// Receive data from the queue. See [RawQueue::receive].
+    pub fn receive(&self, flags: ReceiveFlags) -> Result<QueueEntry<T>, QueueError> {
+        self.queue.receive(
+            |w, v| self.waiter.wait(w, v),
+            |w| self.waiter.wake(w),
+            flags,
+        )
+    }
+}
+
+impl<T, Q> core::ops::Deref for WaiterQueue<T, Q> {
+    type Target = RawQueue<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// A copy of the full state of a queue, captured by [RawQueue::capture_state] and reinstated by
+/// [RawQueue::restore_state]. With the serde feature, this can be serialized, for example to write
+/// a checkpoint of a paused system's in-flight queue contents to durable storage.
+pub struct QueueSnapshot<T> {
+    /// The log2 of the length of the captured queue.
+    pub l2len: usize,
+    /// The stride of the captured queue.
+    pub stride: usize,
+    /// The position of the tail of the captured queue.
+    pub tail: u32,
+    /// The info tags and data of the published entries, in the order they will be received.
+    pub entries: Vec<(Info, T)>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<T: Copy + EntryData> RawQueue<T> {
+    /// Capture the counters and all published entries of the queue.
+    ///
+    /// The queue must be quiescent: no other thread may submit to or receive from this queue until
+    /// this function returns. Otherwise, the snapshot may be inconsistent.
+    // With narrow-counters, CounterValue is already a u32.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn capture_state(&self) -> QueueSnapshot<T> {
+        let hdr = self.hdr();
+        let t = hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        let b = hdr.bell.load(Ordering::SeqCst);
+        let count = (b.wrapping_sub(t) & COUNTER_MASK) as usize;
+        let entries = (0..count.min(hdr.len()))
+            .map_while(|i| {
+                let t = hdr.get_ready_ahead(i, self.buf).ok()?;
+                Some(unsafe { QueueEntry::read_from(self.get_buf(t as usize)) })
+            })
+            .filter(|item| !item.is_abandoned())
+            .map(|item| (item.info(), item.data))
+            .collect();
+        QueueSnapshot {
+            l2len: hdr.l2len,
+            stride: hdr.stride,
+            tail: t as u32,
+            entries,
+        }
+    }
+
+    /// Reconstruct the state captured by [RawQueue::capture_state] in this queue, overwriting its
+    /// current contents. Afterwards, the queue will return the captured entries, in order, at the
+    /// same positions they were captured at. This queue must have the same length and stride as the
+    /// captured queue, otherwise Err([QueueError::Unknown]) is returned.
+    ///
+    /// The queue must be quiescent: no other thread may submit to or receive from this queue until
+    /// this function returns.
+    pub fn restore_state(&self, snapshot: &QueueSnapshot<T>) -> Result<(), QueueError> {
+        let hdr = self.hdr();
+        if snapshot.l2len != hdr.l2len
+            || snapshot.stride != hdr.stride
+            || snapshot.entries.len() > hdr.len()
+            || snapshot.tail > POSITION_MASK
+        {
+            return Err(QueueError::Unknown);
+        }
+        // Write every slot, so that the slots past the restored entries are marked as belonging to
+        // the previous go-around of the queue, and so appear empty to the consumer.
+        for i in 0..hdr.len() as u32 {
+            let h = snapshot.tail.wrapping_add(i) & POSITION_MASK;
+            let buf_item = self.get_buf(h as usize);
+            let cmd_slot = match snapshot.entries.get(i as usize) {
+                Some(&(info, data)) => {
+                    *buf_item = QueueEntry::new(info, data);
+                    hdr.cmd_slot(h, false)
+                }
+                None => hdr.cmd_slot(h, false) ^ CMD_SLOT_TURN,
+            };
+            buf_item.publish(cmd_slot);
+        }
+        let end = snapshot.tail.wrapping_add(snapshot.entries.len() as u32) & POSITION_MASK;
+        hdr.head.store(end, Ordering::SeqCst);
+        hdr.waiters.store(0, Ordering::SeqCst);
+        hdr.bell.store(end as CounterValue, Ordering::SeqCst);
+        hdr.tail
+            .store(snapshot.tail as CounterValue, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+/// Wait for receiving on multiple raw queues. If any of the passed raw queues can return data, they
+/// will do so by writing it into the output array at the same index that they are in the `queues`
+/// variable. The queues and output arrays must be the same length. If no data is available in any
+/// queues, then the function will call back on multi_wait, which it expects to wait until **any**
+/// of the pairs (&x, y) meet the condition that *x != y. Before returning any data, the function
+/// will callback on multi_ring, to inform multiple queues that data was taken from them. It expects
+/// the multi_ring function to wake up any waiting threads on the supplied words of memory.
+///
+/// Note that both call backs specify the pointers as Option. In the case that an entry is None,
+/// there was no requested wait or wake operation for that queue, and that entry should be ignored.
+///
+/// If flags specifies [ReceiveFlags::NON_BLOCK], then if no data is available, the function returns
+/// immediately with Err([QueueError::WouldBlock]). If no data is available, and some queue's next
+/// entry is corrupt, the function returns Err([QueueError::Corrupt]) instead of waiting.
+///
+/// # Rationale
+/// This function is here to implement poll or select like functionality, wherein a given thread or
+/// program wants to wait on multiple incoming request channels and handle them itself, thus cutting
+/// down on the number of threads required. The maximum number of queues to use here is a trade-off
+/// --- more means fewer threads, but since this function is linear in the number of queues, each
+/// thread could take longer to service requests.
+///
+/// The complexity of the multi_wait and multi_ring callbacks is present to avoid calling into the
+/// kernel often for high-contention queues.
+pub fn multi_receive<
+    T: Copy + EntryData,
+    W: Fn(&[(Option<&Counter>, CounterValue)]),
+    R: Fn(&[Option<&Counter>]),
+>(
+    queues: &[&RawQueue<T>],
+    output: &mut [Option<QueueEntry<T>>],
+    multi_wait: W,
+    multi_ring: R,
+    flags: ReceiveFlags,
+) -> Result<usize, QueueError> {
+    if output.len() != queues.len() {
+        return Err(QueueError::Unknown);
+    }
+    /* TODO (opt): avoid this allocation until we have to sleep */
+    let mut waiters = Vec::new();
+    waiters.resize(queues.len(), Default::default());
+    let mut ringers = Vec::new();
+    ringers.resize(queues.len(), None);
+    let mut attempts = 100;
+    loop {
+        let mut count = 0;
+        let mut closed = 0;
+        let mut corrupt = false;
+        for (i, q) in queues.iter().enumerate() {
+            let res = q.setup_sleep(
+                attempts == 0,
+                &mut output[i],
+                &mut waiters[i],
+                &mut ringers[i],
+            );
+            match res {
+                Ok(()) => count += 1,
+                Err(QueueError::Closed) => closed += 1,
+                Err(QueueError::Corrupt) => corrupt = true,
+                Err(_) => {}
+            }
+        }
+        if count > 0 {
+            multi_ring(&ringers);
+            return Ok(count);
+        }
+        if corrupt {
+            return Err(QueueError::Corrupt);
+        }
+        if closed == queues.len() {
+            return Err(QueueError::Closed);
+        }
+        if flags.contains(ReceiveFlags::NON_BLOCK) {
+            return Err(QueueError::WouldBlock);
+        }
+        if attempts > 0 {
+            attempts -= 1;
+        } else {
+            multi_wait(&waiters);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    #![allow(soft_unstable)]
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    //   use syscalls::SyscallArgs;
+    use crate::{buffer_len, max_capacity, multi_receive};
+    use crate::{
+        spin_wait as wait, spin_wake as wake, Backoff, Counter, CounterValue, Info,
+        InvariantViolation, Notified, QueueEntry, QueueError, QueueMode, QueueWaiter, RawQueue,
+        RawQueueHdr, ReceiveFlags, SpinThenPark, SubmissionFlags, Submitted, CMD_SLOT_TURN,
+        MAX_L2LEN,
+    };
+
+    #[test]
+    fn it_transmits() {
+        let qh = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 4];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        for i in 0..100 {
+            let res = q.submit(
+                QueueEntry::new(i as Info, i * 10),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert!(res.is_ok());
+            assert_eq!(res.unwrap().info(), i as Info);
+            assert_eq!(res.unwrap().item(), i * 10);
+        }
+    }
+
+    #[test]
+    fn it_fills() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.submit(QueueEntry::new(3, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.submit(QueueEntry::new(4, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.submit(
+            QueueEntry::new(1, 7),
+            wait,
+            wake,
+            SubmissionFlags::NON_BLOCK,
+        );
+        assert_eq!(res, Err(QueueError::WouldBlock));
+    }
+
+    #[test]
+    fn it_nonblock_receives() {
+        let qh = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 4];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap().info(), 1);
+        assert_eq!(res.unwrap().item(), 7);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+    }
+
+    #[test]
+    fn it_multi_receives() {
+        let qh1 = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer1 = [QueueEntry::<i32>::default(); 1 << 4];
+        let q1 = unsafe { RawQueue::new(&qh1, buffer1.as_mut_ptr()) };
+
+        let qh2 = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer2 = [QueueEntry::<i32>::default(); 1 << 4];
+        let q2 = unsafe { RawQueue::new(&qh2, buffer2.as_mut_ptr()) };
+
+        let res = q1.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q2.submit(QueueEntry::new(2, 8), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+
+        let mut output = [None, None];
+        let res = multi_receive(
+            &[&q1, &q2],
+            &mut output,
+            |_| {},
+            |_| {},
+            ReceiveFlags::empty(),
+        );
+        assert_eq!(res, Ok(2));
+        assert_eq!(output[0].unwrap().info(), 1);
+        assert_eq!(output[0].unwrap().item(), 7);
+        assert_eq!(output[1].unwrap().info(), 2);
+        assert_eq!(output[1].unwrap().item(), 8);
+    }
+
+    #[test]
+    fn it_transmits_across_threads() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            for i in 0..200 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_submits_and_waits() {
+        let qh1 = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer1 = [QueueEntry::<i32>::default(); 1 << 2];
+        let req = unsafe { RawQueue::new(&qh1, buffer1.as_mut_ptr()) };
+
+        let qh2 = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer2 = [QueueEntry::<i32>::default(); 1 << 2];
+        let resp = unsafe { RawQueue::new(&qh2, buffer2.as_mut_ptr()) };
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..2 {
+                    let r = req.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+                    // Complete someone else's request first, to deliver out of order.
+                    let other = QueueEntry::new(r.info() + 100, 0);
+                    let res = resp.submit(other, wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                    let done = QueueEntry::new(r.info(), r.item() * 2);
+                    let res = resp.submit(done, wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            let mut dispatched = vec![];
+            for i in 0..2 {
+                let res = req.submit_and_wait(QueueEntry::new(i, 21), &resp, wait, wake, |c| {
+                    dispatched.push(c.info())
+                });
+                assert_eq!(res.unwrap().info(), i);
+                assert_eq!(res.unwrap().item(), 42);
+            }
+            assert_eq!(dispatched, [100, 101]);
+        });
+    }
+
+    #[test]
+    fn it_tries_receive() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        assert!(q.try_receive(wake).unwrap().is_none());
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        let mut next = 0;
+        while let Some(item) = q.try_receive(wake).unwrap() {
+            assert_eq!(item.info(), next);
+            next += 1;
+        }
+        assert_eq!(next, 3);
+    }
+
+    #[test]
+    fn it_times_out_receive() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let ticks = AtomicU32::new(0);
+        let deadline = || ticks.fetch_add(1, Ordering::SeqCst) >= 3;
+        let tick_wait = |_: &Counter, _| {};
+        let res = q.receive_timeout(tick_wait, wake, ReceiveFlags::empty(), deadline);
+        assert_eq!(res.unwrap_err(), QueueError::TimedOut);
+        assert_eq!(ticks.load(Ordering::SeqCst), 4);
+        assert!(!qh.consumer_waiting());
+
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive_timeout(wait, wake, ReceiveFlags::empty(), || true);
+        assert_eq!(res.unwrap().info(), 1);
+    }
+
+    #[test]
+    fn it_times_out_submit() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        for i in 0..4 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        let ticks = AtomicU32::new(0);
+        let deadline = || ticks.fetch_add(1, Ordering::SeqCst) >= 3;
+        let tick_wait = |_: &Counter, _| {};
+        let item = QueueEntry::new(4, 7);
+        let res = q.submit_timeout(item, tick_wait, wake, SubmissionFlags::empty(), deadline);
+        assert_eq!(res, Err(QueueError::TimedOut));
+        assert_eq!(ticks.load(Ordering::SeqCst), 4);
+        // Timing out must not have claimed a slot.
+        assert_eq!(qh.head.load(Ordering::SeqCst), 4);
+        assert!(!qh.submitter_waiting());
+
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+        let res = q.submit_timeout(item, wait, wake, SubmissionFlags::empty(), || true);
+        assert_eq!(res, Ok(()));
+        for i in 1..5 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+    }
+
+    #[test]
+    fn it_peeks() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        assert!(q.peek().is_none());
+        assert!(!qh.consumer_waiting());
+        for i in 0..2 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(q.peek().unwrap().info(), 0);
+        assert_eq!(q.peek().unwrap().info(), 0);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+
+        // Abandoned entries are skipped, just like receive does.
+        drop(q.reserve_contiguous(1, wait, wake, SubmissionFlags::empty()));
+        assert!(q.peek().is_none());
+        let res = q.submit(QueueEntry::new(5, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        assert_eq!(q.peek().unwrap().info(), 5);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 5);
+    }
+
+    #[test]
+    fn it_peeks_infos() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let mut out = [0; 4];
+        assert_eq!(q.peek_infos(&mut out), 0);
+
+        q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        drop(q.reserve_contiguous(1, wait, wake, SubmissionFlags::empty()));
+        for i in 2..4 {
+            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+        }
+        // The abandoned entry is skipped, and nothing is consumed.
+        assert_eq!(q.peek_infos(&mut out), 3);
+        assert_eq!(out[..3], [1, 2, 3]);
+        assert_eq!(q.peek_infos(&mut out[..2]), 2);
+        assert_eq!(out[..2], [1, 2]);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+        assert_eq!(q.peek_infos(&mut out), 2);
+        assert_eq!(out[..2], [2, 3]);
+    }
+
+    #[test]
+    fn it_publishes_data_with_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<[u64; 8]>>());
+        let mut buffer = [QueueEntry::<[u64; 8]>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        // Consumers must never see a published entry with its data only partly written.
+        std::thread::scope(|s| {
+            for p in 0..2 {
+                let q = &q;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        let item = QueueEntry::new(p, [i; 8]);
+                        let res = q.submit(item, wait, wake, SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+            }
+            let mut next = [0; 2];
+            for _ in 0..400 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+                let p = res.info() as usize;
+                assert_eq!(res.item(), [next[p]; 8]);
+                next[p] += 1;
+            }
+        });
+    }
+
+    #[test]
+    fn it_peeks_ahead() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        assert_eq!(q.peek_ahead(0).unwrap_err(), QueueError::WouldBlock);
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(q.peek_ahead(0).unwrap().info(), 0);
+        assert_eq!(q.peek_ahead(2).unwrap().info(), 2);
+        assert_eq!(q.peek_ahead(3).unwrap_err(), QueueError::WouldBlock);
+        assert_eq!(q.peek_ahead(4).unwrap_err(), QueueError::WouldBlock);
+
+        // Lookahead must follow the tail around the end of the buffer.
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+        for i in 3..5 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(q.peek_ahead(0).unwrap().info(), 1);
+        assert_eq!(q.peek_ahead(3).unwrap().info(), 4);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+    }
+
+    #[test]
+    fn it_idles() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let idles = AtomicU32::new(0);
+        let idle = || {
+            if idles.fetch_add(1, Ordering::SeqCst) == 100 {
+                let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(()));
+            }
+        };
+        let res = q.receive_with_idle(wait, wake, idle, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+        assert_eq!(idles.load(Ordering::SeqCst), 101);
+    }
+
+    #[test]
+    fn it_drains_matching_prefix() {
+        let qh = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 4];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        for i in [2, 4, 6, 7, 8] {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        let drained: Vec<_> = q
+            .drain_filter(wake, |e| e.info() % 2 == 0)
+            .map(|e| e.info())
+            .collect();
+        assert_eq!(drained, [2, 4, 6]);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap().info(), 7);
+        assert_eq!(q.drain_filter(wake, |_| true).count(), 1);
+        assert_eq!(q.drain_filter(wake, |_| true).count(), 0);
+    }
+
+    #[test]
+    fn it_restores_state() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        for i in 0..6 {
+            let res = q.submit(
+                QueueEntry::new(i, i as i32),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            if i < 3 {
+                q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            }
+        }
+        let snapshot = q.capture_state();
+        assert_eq!(snapshot.tail, 3);
+        assert_eq!(snapshot.entries, [(3, 3), (4, 4), (5, 5)]);
+
+        let qh2 = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer2 = [QueueEntry::<i32>::default(); 1 << 2];
+        let q2 = unsafe { RawQueue::new(&qh2, buffer2.as_mut_ptr()) };
+        assert_eq!(q2.restore_state(&snapshot), Ok(()));
+        for i in 3..6 {
+            let res = q2.receive(wait, wake, ReceiveFlags::NON_BLOCK).unwrap();
+            assert_eq!((res.info(), res.item()), (i, i as i32));
+        }
+        let res = q2.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        for i in 6..12 {
+            let res = q2.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            let res = q2.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+            assert_eq!(res.unwrap().info(), i);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_state() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u64>>());
+        let mut buffer = [QueueEntry::<u64>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+
+        let json = serde_json::to_string(&q.capture_state()).unwrap();
+        let snapshot: crate::QueueSnapshot<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.entries, [(1, 7)]);
+    }
+
+    // Owned payloads aren't plain data, so they can't be checksummed.
+    #[cfg(not(feature = "checksum"))]
+    #[test]
+    fn it_moves_owned_payloads() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<String>>());
+        let mut buffer: Vec<QueueEntry<String>> = (0..4).map(|_| QueueEntry::default()).collect();
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        // Go around the buffer several times, so that every slot is reused after its previous
+        // payload was moved out.
+        for i in 0..10 {
+            let res = q.submit(
+                QueueEntry::new(i, format!("item {}", i)),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            let (info, item) = q
+                .receive(wait, wake, ReceiveFlags::empty())
+                .unwrap()
+                .info_and_item();
+            assert_eq!(info, i);
+            assert_eq!(item, format!("item {}", i));
+        }
+        // The buffer still holds stale copies of the payloads that were moved out, which must
+        // not be dropped a second time.
+        std::mem::forget(buffer);
+
+        // Each payload is owned by exactly one side at a time.
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<std::sync::Arc<()>>>());
+        let mut buffer: Vec<QueueEntry<std::sync::Arc<()>>> =
+            (0..4).map(|_| QueueEntry::default()).collect();
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let tracker = std::sync::Arc::new(());
+        for i in 0..10 {
+            let res = q.submit(
+                QueueEntry::new(i, tracker.clone()),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            assert_eq!(std::sync::Arc::strong_count(&tracker), 2);
+            drop(q.receive(wait, wake, ReceiveFlags::empty()).unwrap());
+            assert_eq!(std::sync::Arc::strong_count(&tracker), 1);
+        }
+        std::mem::forget(buffer);
+    }
+
+    #[test]
+    fn it_reports_stats() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+
+        let stats = qh.stats();
+        assert_eq!((stats.head, stats.tail, stats.bell), (3, 1, 3));
+        assert_eq!(stats.len, 4);
+        assert_eq!(stats.occupancy, 2);
+        assert!(!stats.consumer_waiting);
+        assert!(!stats.submitters_waiting);
+
+        qh.consumer_set_waiting(true);
+        let stats = qh.stats();
+        assert!(stats.consumer_waiting);
+        assert_eq!(stats.tail, 1);
+    }
+
+    #[test]
+    fn it_reports_capacity() {
+        let qh = RawQueueHdr::new(3, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 3];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        assert_eq!(qh.capacity(), 8);
+        assert_eq!(q.capacity(), 8);
+        assert_eq!(q.l2_capacity(), 3);
+        assert_eq!(q.stride(), std::mem::size_of::<QueueEntry<u32>>());
+    }
+
+    #[test]
+    fn it_reports_empty_and_full() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        assert!(q.is_empty());
+        assert!(!q.is_full());
+
+        for i in 0..4 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            assert!(!q.is_empty());
+        }
+        assert!(q.is_full());
+
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+        assert!(!q.is_full());
+    }
+
+    #[test]
+    fn it_resets_headers() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 0);
+
+        qh.reset();
+        let q = unsafe { RawQueue::new_zeroed(&qh, buffer.as_mut_ptr()) };
+        assert!(q.is_empty());
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        let res = q.submit(QueueEntry::new(9, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 9);
+    }
+
+    #[test]
+    fn it_closes() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        // Like a futex wait, return when woken even if the word hasn't changed.
+        let wait = |x: &Counter, v: CounterValue| {
+            while x.load(Ordering::SeqCst) == v && !qh.is_closed() {
+                core::hint::spin_loop();
+            }
+        };
+
+        for i in 0..4 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        std::thread::scope(|s| {
+            let submitter =
+                s.spawn(|| q.submit(QueueEntry::new(4, 7), wait, wake, SubmissionFlags::empty()));
+            while !qh.stats().submitters_waiting {
+                core::hint::spin_loop();
+            }
+            qh.close(wake);
+            assert_eq!(submitter.join().unwrap(), Err(QueueError::Closed));
+        });
+
+        // Entries published before the close can still be received.
+        for i in 0..4 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap_err(), QueueError::Closed);
+        let res = q.submit(
+            QueueEntry::new(5, 7),
+            wait,
+            wake,
+            SubmissionFlags::NON_BLOCK,
+        );
+        assert_eq!(res, Err(QueueError::Closed));
+
+        // A consumer that's already waiting is released as well.
+        qh.reset();
+        std::thread::scope(|s| {
+            let consumer = s.spawn(|| q.receive(wait, wake, ReceiveFlags::empty()).unwrap_err());
+            while !qh.stats().consumer_waiting {
+                core::hint::spin_loop();
+            }
+            qh.close(wake);
+            assert_eq!(consumer.join().unwrap(), QueueError::Closed);
+        });
+    }
+
+    #[test]
+    fn it_closes_under_parked_waiters() {
+        // Spinning waiters are never woken by a ring, only by the word they wait on changing, so
+        // a close that just rang them would leave them waiting forever.
+        let full = RawQueueHdr::with_spin_limit(1, std::mem::size_of::<QueueEntry<u32>>(), 0);
+        let empty = RawQueueHdr::with_spin_limit(1, std::mem::size_of::<QueueEntry<u32>>(), 0);
+        let mut fbuf = [QueueEntry::<u32>::default(); 1 << 1];
+        let mut ebuf = [QueueEntry::<u32>::default(); 1 << 1];
+        let fq = unsafe { RawQueue::new(&full, fbuf.as_mut_ptr()) };
+        let eq = unsafe { RawQueue::new(&empty, ebuf.as_mut_ptr()) };
+        for i in 0..2 {
+            let res = fq.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+
+        std::thread::scope(|s| {
+            let submitter =
+                s.spawn(|| fq.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty()));
+            let consumer = s.spawn(|| eq.receive(wait, wake, ReceiveFlags::empty()).unwrap_err());
+            while !full.stats().submitters_waiting || !empty.stats().consumer_waiting {
+                core::hint::spin_loop();
+            }
+            full.close(wake);
+            empty.close(wake);
+            assert_eq!(submitter.join().unwrap(), Err(QueueError::Closed));
+            assert_eq!(consumer.join().unwrap(), QueueError::Closed);
+        });
+
+        // Closing only flipped bits outside the positions.
+        assert_eq!(full.stats().occupancy, 2);
+        assert_eq!(
+            fq.receive(wait, wake, ReceiveFlags::empty())
+                .unwrap()
+                .info(),
+            0
+        );
+    }
+
+    #[test]
+    fn it_receives_in_place() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..2 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+
+        let guard = q.receive_ref(wait, wake, ReceiveFlags::empty()).unwrap();
+        assert_eq!(guard.info(), 0);
+        assert_eq!(guard.item(), 7);
+        // The slot is still held, so the tail hasn't moved.
+        assert_eq!(qh.stats().tail, 0);
+        #[cfg(not(feature = "debug-checks"))]
+        assert_eq!(
+            q.receive(wait, wake, ReceiveFlags::NON_BLOCK).unwrap_err(),
+            QueueError::Reentrant
+        );
+        drop(guard);
+
+        assert_eq!(qh.stats().tail, 1);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+    }
+
+    #[test]
+    fn it_submits_in_place() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<[u32; 4]>>());
+        let mut buffer = [QueueEntry::<[u32; 4]>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let res = q.submit_in_place(
+            |entry| {
+                for (i, x) in entry.data.iter_mut().enumerate() {
+                    *x = i as u32;
+                }
+            },
+            3,
+            wait,
+            wake,
+            SubmissionFlags::empty(),
+        );
+        assert_eq!(res, Ok(()));
+
+        // A fill that panics abandons the slot.
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            q.submit_in_place(
+                |_| panic!("fill failed"),
+                4,
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            )
+        }));
+        assert!(res.is_err());
+
+        let res = q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+        assert_eq!(res.info_and_item(), (3, [0, 1, 2, 3]));
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+    }
+
+    #[test]
+    fn it_survives_consumer_panics() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let submit = |i| {
+            q.submit_timeout(
+                QueueEntry::new(i, 7),
+                wait,
+                wake,
+                SubmissionFlags::NON_BLOCK,
+                || false,
+            )
+        };
+        for i in 0..4 {
+            assert_eq!(submit(i), Ok(()));
+        }
+
+        // A consumer that panics while holding a received entry still releases its slot.
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let item = q.receive_ref(wait, wake, ReceiveFlags::empty()).unwrap();
+            assert_eq!(item.info(), 0);
+            panic!("consumer failed");
+        }));
+        assert!(res.is_err());
+        assert_eq!(submit(4), Ok(()));
+
+        // As does one that panics partway through a run of entries, and the entries handed out
+        // before the panic aren't received again.
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            q.receive_many(4, wake, |item| {
+                if item.info() == 2 {
+                    panic!("consumer failed");
+                }
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(submit(5), Ok(()));
+        assert_eq!(submit(6), Ok(()));
+        for i in 3..7 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        assert_eq!(q.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn it_lays_out_regions() {
+        type Entry = QueueEntry<u64>;
+        let layout = RawQueue::<u64>::layout(3);
+        let offset = RawQueue::<u64>::buffer_offset(3);
+        assert!(offset >= std::mem::size_of::<RawQueueHdr>());
+        assert_eq!(offset % std::mem::align_of::<Entry>(), 0);
+        assert!(layout.size() >= offset + 8 * std::mem::size_of::<Entry>());
+        assert_eq!(layout.align(), std::mem::align_of::<RawQueueHdr>().max(8));
+
+        // Build a queue in a region allocated with the layout.
+        unsafe {
+            let region = std::alloc::alloc(layout);
+            let hdr = region as *mut RawQueueHdr;
+            hdr.write(RawQueueHdr::new(3, std::mem::size_of::<Entry>()));
+            let q = RawQueue::new_zeroed(hdr, region.add(offset) as *mut Entry);
+            let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), 1);
+            std::alloc::dealloc(region, layout);
+        }
+    }
+
+    #[test]
+    fn it_checks_headers() {
+        use crate::QueueLayoutError;
+
+        let stride = std::mem::size_of::<QueueEntry<u64>>();
+        let qh = RawQueueHdr::new_checked::<u64>(4, stride).unwrap();
+        assert_eq!(qh.capacity(), 16);
+        let qh = RawQueueHdr::new_checked::<u64>(MAX_L2LEN, stride).unwrap();
+        assert_eq!(qh.capacity(), max_capacity());
+        assert_eq!(max_capacity(), 1 << 30);
+
+        let res = RawQueueHdr::new_checked::<[u64; 2]>(4, stride);
+        assert_eq!(res.err(), Some(QueueLayoutError::StrideMismatch));
+        let res = RawQueueHdr::new_checked::<u64>(MAX_L2LEN + 1, stride);
+        assert_eq!(res.err(), Some(QueueLayoutError::CapacityTooLarge));
+        let res = std::panic::catch_unwind(|| RawQueueHdr::new(MAX_L2LEN + 1, stride));
+        assert!(res.is_err());
+        let res = RawQueueHdr::new_checked::<u64>(usize::BITS as usize, stride);
+        assert_eq!(res.err(), Some(QueueLayoutError::CapacityOverflow));
+    }
+
+    #[test]
+    fn it_reports_waiters() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        assert_eq!(qh.submitters_waiting(), 0);
+        assert!(!qh.consumer_is_waiting());
+
+        qh.inc_submit_waiting();
+        qh.inc_submit_waiting();
+        qh.consumer_set_waiting(true);
+        assert_eq!(qh.submitters_waiting(), 2);
+        assert!(qh.consumer_is_waiting());
+    }
+
+    #[test]
+    fn it_dumps_headers() {
+        let stride = std::mem::size_of::<QueueEntry<i32>>();
+        let qh = RawQueueHdr::new(2, stride);
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..6 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            if i < 5 {
+                q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            }
+        }
+        qh.consumer_set_waiting(true);
+
+        let snapshot = qh.debug_snapshot();
+        assert_eq!((snapshot.head, snapshot.bell, snapshot.tail), (6, 6, 5));
+        assert_eq!((snapshot.turn, snapshot.occupancy), (1, 1));
+        assert!(snapshot.consumer_waiting);
+        assert!(!snapshot.submitters_waiting);
+        let dump = format!("{:?}", snapshot);
+        assert_eq!(
+            dump,
+            format!(
+                "queue of 4 entries (l2len 2), stride {}\n  head 6, bell 6, tail 5 (turn 1), 1 \
+                 published and not received\n  consumer waiting, 0 submitter(s) waiting",
+                stride
+            )
+        );
+    }
+
+    #[test]
+    fn it_initializes_buffers() {
+        use std::mem::MaybeUninit;
+
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        // Fill the buffer with garbage that looks like ready entries.
+        let mut buffer = [MaybeUninit::new(QueueEntry::<u32>::default()); 1 << 2];
+        for slot in buffer.iter_mut() {
+            let entry = QueueEntry::<u32>::new(9, 9);
+            entry.set_cmd_slot(u32::MAX);
+            slot.write(entry);
+        }
+
+        let q = unsafe { RawQueue::new(&qh, RawQueue::init_buffer(&mut buffer)) };
+        qh.bell.store(1, Ordering::SeqCst);
+        // Even with the bell suggesting otherwise, nothing is ready until it's submitted.
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        qh.bell.store(0, Ordering::SeqCst);
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap().info(), 1);
+    }
+
+    #[test]
+    fn it_keeps_turns_with_any_info_width() {
+        // The cmd_slot is always the first 32 bits of the entry, however wide the info tag is.
+        assert_eq!(std::mem::offset_of!(QueueEntry<u8>, cmd_slot), 0);
+        assert_eq!(
+            std::mem::size_of_val(&QueueEntry::<u8>::default().cmd_slot),
+            4
+        );
+
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u8>>());
+        let mut buffer = [QueueEntry::<u8>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        // Use info tags with every bit set, across several laps of the buffer, so that turns flip.
+        let base = Info::MAX - 20;
+        for i in 0..12 {
+            let res = q.submit(
+                QueueEntry::new(base + i, 7),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            assert!(q.peek().is_some());
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), base + i);
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+            assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        }
+    }
+
+    #[test]
+    fn it_validates_buffers() {
+        use crate::QueueLayoutError;
+
+        let stride = std::mem::size_of::<QueueEntry<u64>>();
+        let qh = RawQueueHdr::new(2, stride);
+        let mut buffer = [QueueEntry::<u64>::default(); 1 << 2];
+        let ptr = buffer.as_mut_ptr();
+        let len = std::mem::size_of_val(&buffer);
+        unsafe {
+            assert!(RawQueue::new_validated(&qh, ptr, len).is_ok());
+            assert_eq!(
+                RawQueue::new_validated(&qh, ptr, len - 1).err(),
+                Some(QueueLayoutError::BufferTooSmall)
+            );
+            assert_eq!(
+                RawQueue::<u64>::new_validated(&qh, ptr.cast::<u8>().add(1).cast(), len).err(),
+                Some(QueueLayoutError::Misaligned)
+            );
+            assert_eq!(
+                RawQueue::new_validated(&qh, ptr.cast::<QueueEntry<[u64; 2]>>(), len).err(),
+                Some(QueueLayoutError::StrideMismatch)
+            );
+        }
+    }
+
+    #[test]
+    fn it_drains() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+
+        let mut drain = q.drain(wake);
+        assert_eq!(drain.next().unwrap().info(), 0);
+        // Entries submitted after the drain started aren't part of it.
+        let res = q.submit(QueueEntry::new(3, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let rest: Vec<_> = drain.map(|e| e.info()).collect();
+        assert_eq!(rest, [1, 2]);
+
+        let rest: Vec<_> = q.drain(wake).map(|e| e.info()).collect();
+        assert_eq!(rest, [3]);
+        assert_eq!(q.drain(wake).count(), 0);
+    }
+
+    #[test]
+    fn it_flushes() {
+        let qh = RawQueueHdr::with_spin_limit(2, std::mem::size_of::<QueueEntry<u32>>(), 0);
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        assert_eq!(qh.flush(wait), Ok(()));
+
+        for i in 0..4 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| assert_eq!(qh.flush(wait), Ok(())));
+            }
+            while qh.submitters_waiting() < 2 {
+                core::hint::spin_loop();
+            }
+            for i in 0..4 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+        assert_eq!(qh.submitters_waiting(), 0);
+    }
+
+    #[test]
+    fn it_tracks_submissions() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..6 {
+            let ticket = q
+                .submit_tracked(QueueEntry::new(0, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+            assert_eq!(ticket.position, i);
+            assert_eq!(ticket.index, i as usize % 4);
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().position(), ticket.position);
+        }
+    }
+
+    #[test]
+    fn it_numbers_received_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            for i in 0..100 {
+                let (seq, item) = q.receive_seq(wait, wake, ReceiveFlags::empty()).unwrap();
+                assert_eq!(seq, i as CounterValue);
+                assert_eq!(item.info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_reports_remaining() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..3 {
+            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+        }
+        for i in 0..3 {
+            let (item, remaining) = q
+                .receive_with_remaining(wait, wake, ReceiveFlags::empty())
+                .unwrap();
+            assert_eq!(item.info(), i);
+            assert_eq!(remaining, 2 - i as usize);
+        }
+        let res = q.receive_with_remaining(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+    }
+
+    #[test]
+    fn it_aligns_entries() {
+        #[repr(C, align(64))]
+        struct Line([u8; 64]);
+
+        assert!(RawQueue::<u32>::aligned_stride(64) > std::mem::size_of::<QueueEntry<u32>>());
+        assert_eq!(RawQueue::<u32>::aligned_stride(64), 64);
+        let layout = RawQueue::<u32>::layout_aligned(2, 64);
+        assert_eq!(RawQueue::<u32>::buffer_offset_aligned(2, 64) % 64, 0);
+        assert!(layout.size() >= RawQueue::<u32>::buffer_offset_aligned(2, 64) + 4 * 64);
+        let res = RawQueueHdr::new_aligned::<u32>(2, 48);
+        assert_eq!(res.err(), Some(crate::QueueLayoutError::Misaligned));
+
+        let qh = RawQueueHdr::new_aligned::<u32>(2, 64).unwrap();
+        assert_eq!(qh.stride(), 64);
+        let mut lines = [Line([0; 64]), Line([0; 64]), Line([0; 64]), Line([0; 64])];
+        let buf = lines.as_mut_ptr().cast::<QueueEntry<u32>>();
+        let len = std::mem::size_of_val(&lines);
+        let q = unsafe { RawQueue::new_validated(&qh, buf, len).unwrap() };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            for i in 0..100 {
+                let item = q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+                assert_eq!(item.info(), i);
+            }
+        });
+        // Each entry went to the start of its own line.
+        for (i, line) in lines.iter().enumerate() {
+            let entry = unsafe { &*(line as *const Line).cast::<QueueEntry<u32>>() };
+            assert_eq!(entry.info() as usize % 4, i);
+        }
+    }
+
+    #[test]
+    fn it_uses_the_stride() {
+        // Leave room after each entry, as if for a larger payload later on.
+        let stride = 2 * std::mem::size_of::<QueueEntry<u32>>();
+        let qh = RawQueueHdr::new_checked::<u32>(2, stride).unwrap();
+        let mut buffer = vec![0u64; 4 * stride / 8];
+        let base = buffer.as_mut_ptr().cast::<u8>();
+        let q = unsafe { RawQueue::new(&qh, base.cast::<QueueEntry<u32>>()) };
+        for i in 0..4 {
+            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+        }
+        for i in 0..4 {
+            let entry = unsafe { &*base.add(i * stride).cast::<QueueEntry<u32>>() };
+            assert_eq!(entry.info(), i as Info);
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i as Info);
+        }
+
+        let res = RawQueueHdr::new_checked::<u32>(2, stride + 1);
+        assert_eq!(res.err(), Some(crate::QueueLayoutError::StrideMismatch));
+    }
+
+    #[test]
+    fn it_receives_into() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<[u64; 32]>>());
+        let mut buffer = [QueueEntry::<[u64; 32]>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let mut slab = vec![std::mem::MaybeUninit::uninit(); 3];
+        for i in 0..3usize {
+            let item = QueueEntry::new(i as Info, [i as u64; 32]);
+            assert_eq!(q.submit(item, wait, wake, SubmissionFlags::empty()), Ok(()));
+        }
+        for (i, dst) in slab.iter_mut().enumerate() {
+            assert_eq!(
+                q.receive_into(dst, wait, wake, ReceiveFlags::empty()),
+                Ok(())
+            );
+            let entry = unsafe { dst.assume_init_ref() };
+            assert_eq!(entry.info(), i as Info);
+            assert_eq!(entry.item(), [i as u64; 32]);
+        }
+        let mut dst = std::mem::MaybeUninit::uninit();
+        let res = q.receive_into(&mut dst, wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res, Err(QueueError::WouldBlock));
+    }
+
+    #[test]
+    fn it_reports_turns() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new_zeroed(&qh, buffer.as_mut_ptr()) };
+        assert!(q.expected_turn_at(0));
+        assert!(q.expected_turn_at(3));
+        assert!(!q.expected_turn_at(4));
+        assert!(q.expected_turn_at(8));
+        assert!(!q.slot_is_ready(0));
+
+        let range = q
+            .reserve_contiguous(2, wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        // Reserved, but not yet published.
+        assert!(!q.slot_is_ready(0));
+        range.publish();
+        assert!(q.slot_is_ready(0));
+        assert!(q.slot_is_ready(1));
+        assert!(!q.slot_is_ready(2));
+        // The slot holds the turn for position 0, not for the next lap.
+        assert!(!q.slot_is_ready(4));
+    }
+
+    #[test]
+    fn it_unreceives() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..4 {
+            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+        }
+        let item = q.receive_ref(wait, wake, ReceiveFlags::empty()).unwrap();
+        assert_eq!(item.info(), 0);
+        item.unreceive();
+        // The slot was never freed, so the queue is still full, and the entry is still first.
+        let res = q.submit_timeout(
+            QueueEntry::new(4, 7),
+            wait,
+            wake,
+            SubmissionFlags::NON_BLOCK,
+            || false,
+        );
+        assert_eq!(res, Err(QueueError::WouldBlock));
+        for i in 0..4 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        assert_eq!(q.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn it_leaves_full_queues_alone() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for round in 0..10 {
+            for i in 0..4 {
+                let res = q.submit(
+                    QueueEntry::new(i, 7),
+                    wait,
+                    wake,
+                    SubmissionFlags::NON_BLOCK,
+                );
+                assert_eq!(res, Ok(()));
+            }
+            let head = qh.head_word().load(Ordering::SeqCst);
+            for _ in 0..1000 {
+                let res = q.submit(
+                    QueueEntry::new(9, 7),
+                    wait,
+                    wake,
+                    SubmissionFlags::NON_BLOCK,
+                );
+                assert_eq!(res, Err(QueueError::WouldBlock));
+                let res = q.reserve_contiguous(2, wait, wake, SubmissionFlags::NON_BLOCK);
+                assert_eq!(res.err(), Some(QueueError::WouldBlock));
+            }
+            // Failed submissions don't reserve anything.
+            assert_eq!(qh.head_word().load(Ordering::SeqCst), head);
+            assert_eq!(q.check_invariants(), Ok(()));
+            for i in 0..4 {
+                let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+                assert_eq!(res.unwrap().info(), i, "round {}", round);
+            }
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+            assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        }
+    }
+
+    #[test]
+    fn it_notifies_waiters() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let rung = std::cell::RefCell::new(Vec::new());
+        let ring = |w: &Counter| rung.borrow_mut().push(w as *const Counter);
+        let (bell, tail): (&Counter, &Counter) = (&qh.bell, &qh.tail);
+
+        qh.notify_all(ring);
+        assert!(rung.borrow().is_empty());
+
+        qh.consumer_set_waiting(true);
+        qh.notify_all(ring);
+        assert_eq!(*rung.borrow(), [bell as *const Counter]);
+
+        rung.borrow_mut().clear();
+        qh.consumer_set_waiting(false);
+        qh.inc_submit_waiting();
+        qh.notify_all(ring);
+        assert_eq!(*rung.borrow(), [tail as *const Counter]);
+        qh.dec_submit_waiting();
+
+        // Nothing changed, so the waiters would go back to waiting.
+        assert_eq!(bell.load(Ordering::SeqCst), 0);
+        assert_eq!(tail.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn it_compares_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let mut sent = std::collections::HashSet::new();
+        for i in 0..6usize {
+            let item = QueueEntry::new(i as Info % 3, i as u32 / 3);
+            let res = q.submit(item, wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            // The received entry has its cmd_slot set, but is still equal to the one submitted.
+            assert_eq!(q.receive(wait, wake, ReceiveFlags::empty()), Ok(item));
+            assert!(sent.insert(item));
+        }
+        assert!(!sent.insert(QueueEntry::new(1, 1)));
+        assert_ne!(QueueEntry::new(1, 1), QueueEntry::new(1, 2));
+        assert_ne!(QueueEntry::new(1, 1), QueueEntry::new(2, 1));
+    }
+
+    #[test]
+    fn it_coalesces_duplicates() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let submit = |info| {
+            q.submit_dedup(
+                QueueEntry::new(info, 7),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            )
+        };
+
+        assert_eq!(submit(1), Ok(Submitted::Enqueued));
+        assert_eq!(submit(1), Ok(Submitted::Coalesced));
+        assert_eq!(submit(2), Ok(Submitted::Enqueued));
+        // Only the last entry is compared.
+        assert_eq!(submit(1), Ok(Submitted::Enqueued));
+        for info in [1, 2, 1] {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), info);
+        }
+        // Once the last entry has been received, a duplicate goes in the queue again, across the
+        // wrap of the buffer.
+        for _ in 0..3 {
+            assert_eq!(submit(1), Ok(Submitted::Enqueued));
+            assert_eq!(submit(1), Ok(Submitted::Coalesced));
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), 1);
+        }
+        // A reserved but unpublished slot isn't compared.
+        assert_eq!(submit(1), Ok(Submitted::Enqueued));
+        let mut range = q
+            .reserve_contiguous(1, wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        *range.entry_mut(0) = QueueEntry::new(1, 7);
+        assert_eq!(submit(1), Ok(Submitted::Enqueued));
+        range.publish();
+        for _ in 0..3 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), 1);
+        }
+        // A closed queue refuses a duplicate too, even with a matching entry still pending.
+        assert_eq!(submit(1), Ok(Submitted::Enqueued));
+        qh.close(wake);
+        assert_eq!(submit(1), Err(QueueError::Closed));
+    }
+
+    #[test]
+    fn it_overwrites_old_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        // A consumer that falls behind sees only the newest entries.
+        for i in 0..10 {
+            assert_eq!(q.submit_overwrite(QueueEntry::new(i, 7), wake), Ok(()));
+        }
+        for i in 6..10 {
+            let res = q.receive_mpmc(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        let res = q.receive_mpmc(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res, Err(QueueError::WouldBlock));
+
+        // With a slow consumer running alongside, entries may be skipped, but never repeated or
+        // reordered, and the last one always arrives.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 10..2000 {
+                    assert_eq!(q.submit_overwrite(QueueEntry::new(i, 7), wake), Ok(()));
+                }
+            });
+            let mut last = 9;
+            while last != 1999 {
+                let info = q
+                    .receive_mpmc(wait, wake, ReceiveFlags::empty())
+                    .unwrap()
+                    .info();
+                assert!(info > last);
+                last = info;
+                for _ in 0..100 {
+                    core::hint::spin_loop();
+                }
+            }
+        });
+
+        q.hdr().close(wake);
+        let res = q.submit_overwrite(QueueEntry::new(0, 7), wake);
+        assert_eq!(res, Err(QueueError::Closed));
+    }
+
+    #[test]
+    fn it_receives_conditionally() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        drop(q.reserve_contiguous(1, wait, wake, SubmissionFlags::empty()));
+        for i in 1..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        // The abandoned slot is skipped, and the entry that doesn't match stays put.
+        let res = q.receive_if(|e| e.info() == 2, wait, wake, ReceiveFlags::empty());
+        assert_eq!(res, Ok(None));
+        let res = q.receive_if(|e| e.info() == 1, wait, wake, ReceiveFlags::empty());
+        assert_eq!(res, Ok(Some(QueueEntry::new(1, 7))));
+        let res = q.receive_if(|_| true, wait, wake, ReceiveFlags::empty());
+        assert_eq!(res, Ok(Some(QueueEntry::new(2, 7))));
+        let res = q.receive_if(|_| true, wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res, Err(QueueError::WouldBlock));
+    }
+
+    #[test]
+    fn it_checks_invariants() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..10 {
+            for _ in 0..(i % 4) + 1 {
+                q.submit(QueueEntry::new(i, 0), wait, wake, SubmissionFlags::empty())
+                    .unwrap();
+                assert_eq!(q.check_invariants(), Ok(()));
+            }
+            while q.receive(wait, wake, ReceiveFlags::NON_BLOCK).is_ok() {
+                assert_eq!(q.check_invariants(), Ok(()));
+            }
+        }
+
+        q.submit(QueueEntry::new(1, 0), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        let t = qh.stats().tail as u32;
+        buffer[t as usize % 4].set_cmd_slot(qh.cmd_slot(t + 8, false));
+        assert_eq!(
+            q.check_invariants(),
+            Err(InvariantViolation::EntryMisplaced)
+        );
+
+        qh.tail.fetch_add(2, Ordering::SeqCst);
+        assert_eq!(qh.check_invariants(), Err(InvariantViolation::TailPastBell));
+        qh.reset();
+        qh.bell.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(qh.check_invariants(), Err(InvariantViolation::BellPastHead));
+        qh.reset();
+        qh.head.fetch_add(5, Ordering::SeqCst);
+        assert_eq!(qh.check_invariants(), Err(InvariantViolation::Overfull));
+    }
+
+    #[test]
+    fn it_backs_off() {
+        struct Counted(u32);
+        impl Backoff for Counted {
+            fn reset(&mut self) {
+                self.0 = 0;
+            }
+
+            fn spin(&mut self) -> bool {
+                self.0 += 1;
+                self.0 <= 3
+            }
+        }
+
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let waits = AtomicU32::new(0);
+        let mut backoff = Counted(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while waits.load(Ordering::SeqCst) == 0 {
+                    std::hint::spin_loop();
+                }
+                q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty())
+                    .unwrap();
+            });
+            let res = q.receive_with_backoff(
+                |x, v| {
+                    waits.fetch_add(1, Ordering::SeqCst);
+                    wait(x, v)
+                },
+                wake,
+                &mut backoff,
+                ReceiveFlags::empty(),
+            );
+            assert_eq!(res.unwrap().item(), 7);
+        });
+        // Three spins, then the one that said to stop.
+        assert_eq!(backoff.0, 4);
+
+        for i in 0..4 {
+            q.submit_with_backoff(
+                QueueEntry::new(i, 0),
+                wait,
+                wake,
+                &mut SpinThenPark::default(),
+                SubmissionFlags::empty(),
+            )
+            .unwrap();
+        }
+        let mut backoff = SpinThenPark::new(0);
+        assert!(!backoff.spin());
+        backoff = SpinThenPark::new(2);
+        assert!(backoff.spin() && backoff.spin() && !backoff.spin());
+        backoff.reset();
+        assert!(backoff.spin());
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn it_detects_corrupt_entries() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let buf = buffer.as_mut_ptr();
+        let q = unsafe { RawQueue::new(&qh, buf) };
+        q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        q.submit(QueueEntry::new(2, 8), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().item(), 7);
+
+        unsafe { (*buf.add(1)).data = 9 };
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap_err(), QueueError::Corrupt);
+        unsafe { (*buf.add(1)).data = 8 };
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().item(), 8);
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn it_detects_corrupt_entries_in_bulk() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let buf = buffer.as_mut_ptr();
+        let q = unsafe { RawQueue::new(&qh, buf) };
+        for (info, data) in [(0, 0), (1, 1), (2, 2)] {
+            q.submit(
+                QueueEntry::new(info, data),
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            )
+            .unwrap();
+        }
+        unsafe { (*buf.add(1)).data = 9 };
+
+        // The run stops at the corrupt entry, which is then reported and left in place.
+        let mut seen = Vec::new();
+        assert_eq!(q.receive_many(4, wake, |e| seen.push(e.item())), Ok(1));
+        assert_eq!(seen, [0]);
+        let res = q.receive_many(4, wake, |e| seen.push(e.item()));
+        assert_eq!(res, Err(QueueError::Corrupt));
+        assert_eq!(q.peek(), None);
+        assert_eq!(q.drain(wake).count(), 0);
+
+        unsafe { (*buf.add(1)).data = 1 };
+        let items: Vec<_> = q.drain(wake).map(|e| e.item()).collect();
+        assert_eq!(items, [1, 2]);
+    }
+
+    #[test]
+    fn it_exposes_words() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let check_bell = |x: &Counter| assert!(std::ptr::eq(x, qh.bell_word()));
+        let check_tail = |x: &Counter| assert!(std::ptr::eq(x, qh.tail_word()));
+        q.submit(
+            QueueEntry::new(1, 7),
+            wait,
+            check_bell,
+            SubmissionFlags::empty(),
+        )
+        .unwrap();
+        assert_eq!(qh.head_word().load(Ordering::SeqCst), 1);
+        assert_eq!(qh.bell_word().load(Ordering::SeqCst), 1);
+        q.receive(wait, check_tail, ReceiveFlags::empty()).unwrap();
+        assert_eq!(qh.tail_word().load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_works_with_one_slot() {
+        let qh =
+            RawQueueHdr::new_checked::<u32>(0, std::mem::size_of::<QueueEntry<u32>>()).unwrap();
+        let mut buffer = [QueueEntry::<u32>::default(); 1];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        assert_eq!(q.capacity(), 1);
+        for i in 0..10 {
+            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                .unwrap();
+            assert!(q.is_full());
+            // The only slot is taken, so the next submission would block.
+            let res = q.submit_timeout(
+                QueueEntry::new(i, 7),
+                wait,
+                wake,
+                SubmissionFlags::NON_BLOCK,
+                || false,
+            );
+            assert_eq!(res, Err(QueueError::WouldBlock));
+            // The turn flips on every go-around, which is every submission.
+            assert_eq!(buffer[0].get_cmd_slot() & CMD_SLOT_TURN == 0, i % 2 == 1);
+
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+            assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+            assert_eq!(q.check_invariants(), Ok(()));
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                        .unwrap();
+                }
+            });
+            for i in 0..100 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_iterates() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                        .unwrap();
+                }
+                qh.close(wake);
+            });
+            let received = q.iter(wait, wake).map(|e| e.info()).collect::<Vec<_>>();
+            assert_eq!(received, (0..100).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn it_submits_all() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let items = || (0..6).map(|i| QueueEntry::new(i, 7));
+        let res = q.submit_all(items(), wait, wake, SubmissionFlags::NON_BLOCK);
+        assert_eq!(res, Err((4, QueueError::WouldBlock)));
+        for i in 0..4 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        let res = q.submit_all(items().skip(4), wait, wake, SubmissionFlags::NON_BLOCK);
+        assert_eq!(res, Ok(2));
+        for i in 4..6 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let res = q.submit_all(items(), wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(6));
+            });
+            for i in 0..6 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_serves_submitters_in_order() {
+        let qh = RawQueueHdr::new(0, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        q.submit(QueueEntry::new(0, 7), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        let q = &q;
+        std::thread::scope(|s| {
+            // Queue up two blocked submitters, one after the other.
+            for i in 1..3u32 {
+                s.spawn(move || {
+                    let item = QueueEntry::new(i as Info, 7);
+                    q.submit(item, wait, wake, SubmissionFlags::empty())
+                        .unwrap();
+                });
+                while qh.head_word().load(Ordering::SeqCst) != i + 1 {
+                    std::hint::spin_loop();
+                }
+            }
+            // A latecomer that keeps retrying can't get in ahead of them.
+            let res = q.submit_timeout(
+                QueueEntry::new(3, 7),
+                wait,
+                wake,
+                SubmissionFlags::NON_BLOCK,
+                || false,
+            );
+            assert_eq!(res, Err(QueueError::WouldBlock));
+            for i in 0..3 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_sizes_buffers() {
+        const L2LEN: usize = 3;
+        let qh = RawQueueHdr::new(L2LEN, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); buffer_len(L2LEN)];
+        assert_eq!(buffer.len(), qh.capacity());
+        let q = unsafe {
+            RawQueue::new_validated(&qh, buffer.as_mut_ptr(), std::mem::size_of_val(&buffer))
+        };
+        assert!(q.is_ok());
+    }
+
+    #[test]
+    fn it_notifies() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let res = q.submit_notify(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(Notified::Skipped));
+        q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), 2);
+            });
+            while !qh.consumer_is_waiting() {
+                std::hint::spin_loop();
+            }
+            let res = q.submit_notify(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(Notified::Rang));
+        });
+    }
+
+    #[cfg(feature = "padded-header")]
+    #[test]
+    fn it_pads_the_header() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let line = |x: *const u8| x as usize / 64;
+        let head = line(qh.head_word() as *const AtomicU32 as *const u8);
+        let bell = line(qh.bell_word() as *const Counter as *const u8);
+        let tail = line(qh.tail_word() as *const Counter as *const u8);
+        assert_ne!(head, bell);
+        assert_ne!(bell, tail);
+        assert_ne!(head, tail);
+    }
+
+    #[test]
+    fn it_maps_entries() {
+        let entry = QueueEntry::<u32>::new(3, 7);
+        entry.set_cmd_slot(5);
+        let mapped = entry.map(|x| x as u64 * 2);
+        assert_eq!(mapped.get_cmd_slot(), 5);
+        assert_eq!(mapped.info_and_item(), (3, 14));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_entries() {
+        let entry = QueueEntry::<u64>::new(3, 0xdeadbeef);
+        entry.set_cmd_slot(5);
+        let json = serde_json::to_string(&entry).unwrap();
+        let back: QueueEntry<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.get_cmd_slot(), 5);
+        assert_eq!(back.info(), 3);
+        assert_eq!(back.item(), 0xdeadbeef);
+    }
+
+    #[test]
+    fn it_reserves_contiguous() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        // Offset the queue so that the reserved range wraps around the end of the buffer.
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+        }
+        let mut range = q
+            .reserve_contiguous(3, wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        assert_eq!(range.len(), 3);
+        for i in (0..3).rev() {
+            *range.entry_mut(i) = QueueEntry::new(10 + i as Info, 0);
+            *range.data_mut(i) = i as i32;
+        }
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+        // The queue isn't empty, but the entries aren't published yet.
+        let flags = ReceiveFlags::NON_BLOCK | ReceiveFlags::REPORT_NOT_READY;
+        let res = q.receive(wait, wake, flags);
+        assert_eq!(res.unwrap_err(), QueueError::NotReady);
+        range.publish();
+        for i in 0..3 {
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK).unwrap();
+            assert_eq!((res.info(), res.item()), (10 + i as Info, i));
+        }
+
+        // Dropping a range without publishing it abandons its slots.
+        let range = q
+            .reserve_contiguous(2, wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        let res = q.submit(QueueEntry::new(20, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        drop(range);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap().info(), 20);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+
+        let res = q.reserve_contiguous(5, wait, wake, SubmissionFlags::empty());
+        assert!(matches!(res, Err(QueueError::Unknown)));
+    }
+
+    #[test]
+    fn it_fills_ranges_out_of_order() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for i in 0..2 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+            q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+        }
+
+        let mut range = q
+            .reserve_contiguous(4, wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        assert_eq!((range.position(0), range.position(3)), (2, 5));
+        assert!(range.turn(1));
+        assert!(!range.turn(2));
+        let (head, tail) = range.as_mut_slices();
+        assert_eq!((head.len(), tail.len()), (2, 2));
+        // Fill each half of the range from a different thread.
+        std::thread::scope(|s| {
+            for (base, slice) in [(0, head), (2, tail)] {
+                s.spawn(move || {
+                    for (i, entry) in slice.iter_mut().enumerate() {
+                        *entry = QueueEntry::new(base + i as Info, 7);
+                    }
+                });
+            }
+        });
+        range.publish();
+
+        for i in 0..4 {
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+            assert_eq!(res.unwrap().info(), i);
+        }
+    }
+
+    #[cfg(feature = "watermark")]
+    #[test]
+    fn it_tracks_max_depth() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        for n in [1, 3, 2] {
+            for i in 0..n {
+                q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty())
+                    .unwrap();
+            }
+            for _ in 0..n {
+                q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            }
+        }
+        assert_eq!(qh.max_depth(), 3);
+        qh.reset_max_depth();
+        assert_eq!(qh.max_depth(), 0);
+        q.submit(QueueEntry::new(0, 7), wait, wake, SubmissionFlags::empty())
+            .unwrap();
+        assert_eq!(qh.max_depth(), 1);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn it_counts_wakes() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+        // Make it look like the consumer went to sleep.
+        q.setup_sleep_simple();
+        let res = q.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+
+        let stats = qh.wake_stats();
+        assert_eq!(stats.wakes_issued, 1);
+        assert_eq!(stats.wakes_suppressed, 1);
+    }
+
+    #[test]
+    fn it_tracks_consumer_presence() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let q2 = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        assert!(!q2.is_consumer_present());
+        q.consumer_attach();
+        assert!(q2.is_consumer_present());
+        q.consumer_detach();
+        assert!(!q2.is_consumer_present());
+    }
+
+    #[test]
+    fn it_sets_modes() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        assert_eq!(q.mode(), None);
+        for mode in [
+            QueueMode::Throughput,
+            QueueMode::LowLatency,
+            QueueMode::PowerSaving,
+        ] {
+            q.set_mode(mode);
+            assert_eq!(q.mode(), Some(mode));
+            assert_eq!(qh.spin_limit(), mode.spin_limit());
+        }
+    }
+
+    #[test]
+    fn it_coalesces_wakes() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let wakes = AtomicU32::new(0);
+        let ring = |_: &Counter| {
+            wakes.fetch_add(1, Ordering::SeqCst);
+        };
+
+        for (mode, expected) in [(QueueMode::LowLatency, 2), (QueueMode::Throughput, 1)] {
+            q.set_mode(mode);
+            wakes.store(0, Ordering::SeqCst);
+            // Pretend the consumer went to sleep, and then two entries arrived before it woke up.
+            qh.consumer_set_waiting(true);
+            for _ in 0..2 {
+                let res = q.submit(QueueEntry::new(1, 7), wait, ring, SubmissionFlags::empty());
+                assert_eq!(res, Ok(()));
+            }
+            assert_eq!(wakes.load(Ordering::SeqCst), expected);
+            assert!(q.receive(wait, wake, ReceiveFlags::NON_BLOCK).is_ok());
+            assert!(q.receive(wait, wake, ReceiveFlags::NON_BLOCK).is_ok());
+        }
+    }
+
+    // Set up a queue over a buffer holding leftover entries from positions 8 onward, and make the
+    // entry at position 1 ready while position 0 is still reserved but not yet written.
+    fn receive_over_stale_buffer(zeroed: bool) -> Result<QueueEntry<i32>, QueueError> {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        for (i, e) in buffer.iter().enumerate() {
+            e.set_cmd_slot(qh.cmd_slot(8 + i as u32, false));
+        }
+        let q = unsafe {
+            if zeroed {
+                RawQueue::new_zeroed(&qh, buffer.as_mut_ptr())
+            } else {
+                RawQueue::new(&qh, buffer.as_mut_ptr())
+            }
+        };
+
+        let slot = q.reserve_contiguous(1, wait, wake, SubmissionFlags::empty());
+        let res = q.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        slot.unwrap().publish();
+        res
+    }
+
+    #[cfg(feature = "debug-checks")]
+    #[test]
+    fn it_detects_stale_entries() {
+        assert_eq!(
+            receive_over_stale_buffer(false).unwrap_err(),
+            QueueError::Corrupt
+        );
+
+        // The bulk receives check each entry too.
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        buffer[0].set_cmd_slot(qh.cmd_slot(8, false));
+        let res = q.receive_many(4, wake, |_| panic!("received a stale entry"));
+        assert_eq!(res, Err(QueueError::Corrupt));
+        assert_eq!(q.drain(wake).count(), 0);
+        let mut out = [QueueEntry::default(); 4];
+        let res = q.receive_batch(&mut out, wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res, Err(QueueError::Corrupt));
+    }
+
+    #[test]
+    fn it_resets_stale_entries() {
+        assert_eq!(
+            receive_over_stale_buffer(true).unwrap_err(),
+            QueueError::WouldBlock
+        );
+    }
+
+    #[test]
+    fn it_submits_batches() {
+        // Start partway through the buffer, so the batch straddles a turn boundary.
+        let qh = RawQueueHdr::with_counters(2, std::mem::size_of::<QueueEntry<u32>>(), 2, 2);
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let items: Vec<_> = (0..6).map(|i| QueueEntry::new(i, i as i32 * 10)).collect();
+        let res = q.submit_batch(&items, wait, wake, SubmissionFlags::NON_BLOCK);
+        assert_eq!(res, Ok(4));
+        let res = q.submit_batch(&items, wait, wake, SubmissionFlags::NON_BLOCK);
+        assert_eq!(res, Err(QueueError::WouldBlock));
+        for i in 0..4 {
+            let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK).unwrap();
+            assert_eq!(res.info(), i);
+            assert_eq!(res.item(), i as i32 * 10);
+        }
+        assert!(q.receive(wait, wake, ReceiveFlags::NON_BLOCK).is_err());
+
+        // A blocking batch longer than the queue goes in several runs.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let items: Vec<_> = (0..50).map(|i| QueueEntry::new(i, 7)).collect();
+                let res = q.submit_batch(&items, wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(50));
+            });
+            for i in 0..50 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_receives_batches() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let mut out = [QueueEntry::default(); 2];
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(
+            q.receive_batch(&mut out, wait, wake, ReceiveFlags::empty()),
+            Ok(2)
+        );
+        assert_eq!(out[0].info(), 0);
+        assert_eq!(out[1].info(), 1);
+        assert_eq!(
+            q.receive_batch(&mut out, wait, wake, ReceiveFlags::empty()),
+            Ok(1)
+        );
+        assert_eq!(out[0].info(), 2);
+        assert_eq!(
+            q.receive_batch(&mut out, wait, wake, ReceiveFlags::NON_BLOCK),
+            Err(QueueError::WouldBlock)
+        );
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            let mut out = [QueueEntry::default(); 3];
+            let mut next = 0;
+            while next < 100 {
+                let n = q
+                    .receive_batch(&mut out, wait, wake, ReceiveFlags::empty())
+                    .unwrap();
+                assert!(n > 0);
+                for e in &out[..n] {
+                    assert_eq!(e.info(), next);
+                    next += 1;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn it_holds_a_waiter() {
+        struct SpinWaiter(AtomicU32);
+
+        impl QueueWaiter for SpinWaiter {
+            fn wait(&self, word: &Counter, expected: CounterValue) {
+                wait(word, expected)
+            }
+
+            fn wake(&self, _word: &Counter) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::with_waiter(&qh, buffer.as_mut_ptr(), (wait, wake)) };
+        assert_eq!(
+            q.submit(QueueEntry::new(1, 7), SubmissionFlags::empty()),
+            Ok(())
+        );
+        assert_eq!(q.receive(ReceiveFlags::empty()).unwrap().info(), 1);
+        assert!(q.peek().is_none());
+
+        let q = unsafe {
+            RawQueue::with_waiter(&qh, buffer.as_mut_ptr(), SpinWaiter(AtomicU32::new(0)))
+        };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    assert_eq!(
+                        q.submit(QueueEntry::new(i, 7), SubmissionFlags::empty()),
+                        Ok(())
+                    );
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(q.receive(ReceiveFlags::empty()).unwrap().info(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn it_limits_spins() {
+        for (spins, idles) in [(0, 0), (100, 100 - 16)] {
+            let stride = std::mem::size_of::<QueueEntry<u32>>();
+            let qh = RawQueueHdr::with_spin_limit(2, stride, spins);
+            let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+            let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+            let idled = AtomicU32::new(0);
+            let idle = || {
+                idled.fetch_add(1, Ordering::SeqCst);
+            };
+            let waited = AtomicU32::new(0);
+            // Once the consumer finally waits, supply an entry.
+            let submit_wait = |x: &Counter, v: CounterValue| {
+                if waited.fetch_add(1, Ordering::SeqCst) == 0 {
+                    let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                }
+                wait(x, v);
+            };
+            let res = q.receive_with_idle(submit_wait, wake, idle, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), 1);
+            assert_eq!(idled.load(Ordering::SeqCst), idles);
+            assert_eq!(waited.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn it_receives_with_multiple_consumers() {
+        const PER_PRODUCER: Info = 250;
+        let qh = RawQueueHdr::new(3, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 3];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let seen: Vec<_> = (0..4 * PER_PRODUCER).map(|_| AtomicU32::new(0)).collect();
+        let received = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for p in 0..4 {
+                let q = &q;
+                s.spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let item = QueueEntry::new(p * PER_PRODUCER + i, 7);
+                        let res = q.submit(item, wait, wake, SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while received.load(Ordering::SeqCst) < seen.len() {
+                        match q.receive_mpmc(wait, wake, ReceiveFlags::NON_BLOCK) {
+                            Ok(item) => {
+                                seen[item.info() as usize].fetch_add(1, Ordering::SeqCst);
+                                received.fetch_add(1, Ordering::SeqCst);
+                            }
+                            Err(QueueError::WouldBlock) => std::thread::yield_now(),
+                            Err(e) => panic!("unexpected error {:?}", e),
+                        }
+                    }
+                });
+            }
+        });
+        assert!(seen.iter().all(|x| x.load(Ordering::SeqCst) == 1));
+    }
+
+    #[test]
+    fn it_receives_many() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        let mut received = Vec::new();
+        for i in 0..3 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(q.receive_many(2, wake, |e| received.push(e.info())), Ok(2));
+        for i in 3..6 {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(q.receive_many(10, wake, |e| received.push(e.info())), Ok(4));
+        assert_eq!(q.receive_many(10, wake, |e| received.push(e.info())), Ok(0));
+        assert_eq!(received, [0, 1, 2, 3, 4, 5]);
+        let res = q.submit(QueueEntry::new(6, 7), wait, wake, SubmissionFlags::empty());
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap().info(), 6);
+    }
+
+    extern crate test;
+
+    fn fill_for_bench(q: &RawQueue<i32>, n: Info) {
+        for i in 0..n {
+            let res = q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+    }
+
+    #[bench]
+    fn drain_with_receive(b: &mut test::Bencher) {
+        let qh = RawQueueHdr::new(10, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = vec![QueueEntry::<i32>::default(); 1 << 10];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        b.iter(|| {
+            fill_for_bench(&q, 1 << 10);
+            while q.receive(wait, wake, ReceiveFlags::NON_BLOCK).is_ok() {}
+        });
     }
 
-    #[inline]
-    fn hdr(&self) -> &RawQueueHdr {
-        unsafe { &*self.hdr }
+    #[bench]
+    fn drain_with_receive_many(b: &mut test::Bencher) {
+        let qh = RawQueueHdr::new(10, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = vec![QueueEntry::<i32>::default(); 1 << 10];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        b.iter(|| {
+            fill_for_bench(&q, 1 << 10);
+            q.receive_many(usize::MAX, wake, |e| {
+                test::black_box(e);
+            })
+            .unwrap();
+        });
     }
 
-    // This is a bit unsafe, but it's because we're managing concurrency ourselves.
-    #[allow(clippy::mut_from_ref)]
-    #[inline]
-    fn get_buf(&self, off: usize) -> &mut QueueEntry<T> {
-        unsafe {
-            (*self.buf.get())
-                .add(off & (self.hdr().len() - 1))
-                .as_mut()
-                .unwrap()
-        }
+    // Total number of entries moved through the queue per iteration of the producer scaling
+    // benches, split evenly among the producers.
+    const SCALING_ITEMS: Info = 1 << 12;
+
+    // Run `producers` threads submitting concurrently into a single consumer, to measure how
+    // contention on the head scales. Throughput is reported in terms of bytes of entries moved.
+    fn bench_producers(b: &mut test::Bencher, producers: Info) {
+        let qh = RawQueueHdr::new(10, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = vec![QueueEntry::<i32>::default(); 1 << 10];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        b.bytes = (SCALING_ITEMS as usize * std::mem::size_of::<QueueEntry<i32>>()) as u64;
+        b.iter(|| {
+            std::thread::scope(|s| {
+                for p in 0..producers {
+                    let q = &q;
+                    s.spawn(move || {
+                        for i in 0..SCALING_ITEMS / producers {
+                            let item = QueueEntry::new(i, p as i32);
+                            let res = q.submit(item, wait, wake, SubmissionFlags::empty());
+                            assert_eq!(res, Ok(()));
+                        }
+                    });
+                }
+                for _ in 0..SCALING_ITEMS {
+                    let res = q.receive(wait, wake, ReceiveFlags::empty());
+                    test::black_box(res.unwrap());
+                }
+            });
+        });
     }
 
-    /// Submit a data item of type T, wrapped in a QueueEntry, to the queue. The two callbacks,
-    /// wait, and ring, are for implementing a rudimentary condvar, wherein if the queue needs to
-    /// block, we'll call wait(x, y), where we are supposed to wait until *x != y. Once we are done
-    /// inserting, if we need to wake up a consumer, we will call ring, which should wake up anyone
-    /// waiting on that word of memory.
-    pub fn submit<W: Fn(&AtomicU64, u64), R: Fn(&AtomicU64)>(
-        &self,
-        item: QueueEntry<T>,
-        wait: W,
-        ring: R,
-        flags: SubmissionFlags,
-    ) -> Result<(), QueueError> {
-        let h = self.hdr().reserve_slot(flags, wait)?;
-        let buf_item = self.get_buf(h as usize);
-        *buf_item = item;
-        let turn = self.hdr().get_turn(h);
-        buf_item.set_cmd_slot(h | if turn { 1u32 << 31 } else { 0 });
+    #[bench]
+    fn producers_01(b: &mut test::Bencher) {
+        bench_producers(b, 1);
+    }
 
-        self.hdr().ring(ring);
-        Ok(())
+    #[bench]
+    fn producers_02(b: &mut test::Bencher) {
+        bench_producers(b, 2);
     }
 
-    /// Receive data from the queue, returning either that data or an error. The wait and ring
-    /// callbacks work similar to [RawQueue::submit].
-    pub fn receive<W: Fn(&AtomicU64, u64), R: Fn(&AtomicU64)>(
-        &self,
-        wait: W,
-        ring: R,
-        flags: ReceiveFlags,
-    ) -> Result<QueueEntry<T>, QueueError> {
-        let t = self
-            .hdr()
-            .get_next_ready(wait, flags, unsafe { *self.buf.get() })?;
-        let buf_item = self.get_buf(t as usize);
-        let item = *buf_item;
-        self.hdr().advance_tail(ring);
-        Ok(item)
+    #[bench]
+    fn producers_04(b: &mut test::Bencher) {
+        bench_producers(b, 4);
     }
 
-    pub fn setup_sleep<'a>(
-        &'a self,
-        sleep: bool,
-        output: &mut Option<QueueEntry<T>>,
-        waiter: &mut (Option<&'a AtomicU64>, u64),
-        ringer: &mut Option<&'a AtomicU64>,
-    ) -> Result<(), QueueError> {
-        let t = self
-            .hdr()
-            .setup_rec_sleep(sleep, unsafe { *self.buf.get() }, waiter)?;
-        let buf_item = self.get_buf(t as usize);
-        let item = *buf_item;
-        *output = Some(item);
-        self.hdr().advance_tail_setup(ringer);
-        Ok(())
+    #[bench]
+    fn producers_08(b: &mut test::Bencher) {
+        bench_producers(b, 8);
     }
 
-    #[inline]
-    pub fn setup_sleep_simple(&self) -> (&AtomicU64, u64) {
-        self.hdr().setup_rec_sleep_simple()
+    #[bench]
+    fn producers_16(b: &mut test::Bencher) {
+        bench_producers(b, 16);
     }
 
-    #[inline]
-    pub fn setup_send_sleep_simple(&self) -> (&AtomicU64, u64) {
-        self.hdr().setup_send_sleep_simple()
+    fn receive_reentrantly() -> Result<QueueEntry<i32>, QueueError> {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        // Simulate something interrupting the consumer while it's waiting, and trying to receive
+        // from the same queue.
+        let inner = std::cell::Cell::new(None);
+        let reentrant_wait = |x: &Counter, v: CounterValue| {
+            if inner.get().is_none() {
+                inner.set(Some(q.receive(wait, wake, ReceiveFlags::NON_BLOCK)));
+                let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(()));
+            }
+            wait(x, v);
+        };
+        let res = q.receive(reentrant_wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+        inner.get().unwrap()
     }
-}
 
-unsafe impl<T: Send> Send for RawQueue<T> {}
-unsafe impl<T: Send> Sync for RawQueue<T> {}
+    #[cfg(not(feature = "debug-checks"))]
+    #[test]
+    fn it_rejects_reentrant_receive() {
+        assert_eq!(receive_reentrantly().unwrap_err(), QueueError::Reentrant);
+    }
 
-#[cfg(any(feature = "std", test))]
-/// Wait for receiving on multiple raw queues. If any of the passed raw queues can return data, they
-/// will do so by writing it into the output array at the same index that they are in the `queues`
-/// variable. The queues and output arrays must be the same length. If no data is available in any
-/// queues, then the function will call back on multi_wait, which it expects to wait until **any**
-/// of the pairs (&x, y) meet the condition that *x != y. Before returning any data, the function
-/// will callback on multi_ring, to inform multiple queues that data was taken from them. It expects
-/// the multi_ring function to wake up any waiting threads on the supplied words of memory.
-///
-/// Note that both call backs specify the pointers as Option. In the case that an entry is None,
-/// there was no requested wait or wake operation for that queue, and that entry should be ignored.
-///
-/// If flags specifies [ReceiveFlags::NON_BLOCK], then if no data is available, the function returns
-/// immediately with Err([QueueError::WouldBlock]).
-///
-/// # Rationale
-/// This function is here to implement poll or select like functionality, wherein a given thread or
-/// program wants to wait on multiple incoming request channels and handle them itself, thus cutting
-/// down on the number of threads required. The maximum number of queues to use here is a trade-off
-/// --- more means fewer threads, but since this function is linear in the number of queues, each
-/// thread could take longer to service requests.
-///
-/// The complexity of the multi_wait and multi_ring callbacks is present to avoid calling into the
-/// kernel often for high-contention queues.
-pub fn multi_receive<T: Copy, W: Fn(&[(Option<&AtomicU64>, u64)]), R: Fn(&[Option<&AtomicU64>])>(
-    queues: &[&RawQueue<T>],
-    output: &mut [Option<QueueEntry<T>>],
-    multi_wait: W,
-    multi_ring: R,
-    flags: ReceiveFlags,
-) -> Result<usize, QueueError> {
-    if output.len() != queues.len() {
-        return Err(QueueError::Unknown);
+    #[cfg(feature = "debug-checks")]
+    #[test]
+    #[should_panic(expected = "reentrant receive")]
+    fn it_rejects_reentrant_receive() {
+        let _ = receive_reentrantly();
     }
-    /* TODO (opt): avoid this allocation until we have to sleep */
-    let mut waiters = Vec::new();
-    waiters.resize(queues.len(), Default::default());
-    let mut ringers = Vec::new();
-    ringers.resize(queues.len(), None);
-    let mut attempts = 100;
-    loop {
-        let mut count = 0;
-        for (i, q) in queues.iter().enumerate() {
-            let res = q.setup_sleep(
-                attempts == 0,
-                &mut output[i],
-                &mut waiters[i],
-                &mut ringers[i],
-            );
-            if res == Ok(()) {
-                count += 1;
-            }
-        }
-        if count > 0 {
-            multi_ring(&ringers);
-            return Ok(count);
-        }
-        if flags.contains(ReceiveFlags::NON_BLOCK) {
-            return Err(QueueError::WouldBlock);
-        }
-        if attempts > 0 {
-            attempts -= 1;
-        } else {
-            multi_wait(&waiters);
-        }
+
+    #[cfg(feature = "bytemuck")]
+    fn entry_bytes<T>(info: crate::Info, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; std::mem::size_of::<QueueEntry<T>>()];
+        let off = std::mem::offset_of!(QueueEntry<T>, info);
+        bytes[off..off + std::mem::size_of::<crate::Info>()].copy_from_slice(&info.to_ne_bytes());
+        let start = std::mem::offset_of!(QueueEntry<T>, data);
+        bytes[start..start + data.len()].copy_from_slice(data);
+        bytes
     }
-}
 
-#[cfg(test)]
-mod tests {
-    #![allow(soft_unstable)]
-    use std::sync::atomic::{AtomicU64, Ordering};
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn it_validates_untrusted_bytes() {
+        use std::num::NonZeroU32;
 
-    //   use syscalls::SyscallArgs;
-    use crate::multi_receive;
-    use crate::{QueueEntry, QueueError, RawQueue, RawQueueHdr, ReceiveFlags, SubmissionFlags};
+        use crate::ValidationError;
 
-    fn wait(x: &AtomicU64, v: u64) {
-        // println!("wait");
-        while x.load(Ordering::SeqCst) == v {
-            core::hint::spin_loop();
-        }
+        let e = QueueEntry::<u32>::try_from_bytes(&entry_bytes::<u32>(3, &7u32.to_ne_bytes()));
+        assert_eq!(e.unwrap().info(), 3);
+        assert_eq!(e.unwrap().item(), 7);
+        assert_eq!(
+            QueueEntry::<u32>::try_from_bytes(&[0; 4]).unwrap_err(),
+            ValidationError::SizeMismatch
+        );
+
+        let e = QueueEntry::<bool>::try_from_bytes(&entry_bytes::<bool>(1, &[1]));
+        assert!(e.unwrap().item());
+        let e = QueueEntry::<bool>::try_from_bytes(&entry_bytes::<bool>(1, &[2]));
+        assert_eq!(e.unwrap_err(), ValidationError::InvalidData);
+
+        let surrogate = 0xd800u32.to_ne_bytes();
+        let e = QueueEntry::<char>::try_from_bytes(&entry_bytes::<char>(1, &surrogate));
+        assert_eq!(e.unwrap_err(), ValidationError::InvalidData);
+
+        // NonZeroU32 uses zero as a niche, so it's not a valid value...
+        let zero = 0u32.to_ne_bytes();
+        let e = QueueEntry::<NonZeroU32>::try_from_bytes(&entry_bytes::<NonZeroU32>(1, &zero));
+        assert_eq!(e.unwrap_err(), ValidationError::InvalidData);
+        // ...but wrapped in an Option, the niche is how None is represented.
+        let e = QueueEntry::<Option<NonZeroU32>>::try_from_bytes(
+            &entry_bytes::<Option<NonZeroU32>>(1, &zero),
+        );
+        assert_eq!(e.unwrap().item(), None);
     }
 
-    fn wake(_x: &AtomicU64) {
-        //   println!("wake");
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn it_casts_shared_bytes() {
+        type Entry = QueueEntry<u64>;
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<Entry>());
+        // Stand-in for a shared object: plain words that the queue's buffer lives in.
+        let mut words = vec![0u64; 4 * std::mem::size_of::<Entry>() / 8];
+        let q = unsafe { RawQueue::new(&qh, words.as_mut_ptr().cast::<Entry>()) };
+        for i in 0..3usize {
+            let item = QueueEntry::new(i as crate::Info, 10 + i as u64);
+            assert_eq!(q.submit(item, wait, wake, SubmissionFlags::empty()), Ok(()));
+        }
+        let entries: &[Entry] = bytemuck::cast_slice(&words);
+        assert_eq!(entries.len(), 4);
+        for (i, entry) in entries[..3].iter().enumerate() {
+            assert_eq!(entry.info(), i as crate::Info);
+            assert_eq!(entry.item(), 10 + i as u64);
+        }
+        assert_eq!(entries[3].info(), 0);
+        assert_eq!(<Entry as bytemuck::Zeroable>::zeroed().info(), 0);
     }
 
     #[test]
-    fn it_transmits() {
+    fn it_tags_producers() {
         let qh = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
         let mut buffer = [QueueEntry::<i32>::default(); 1 << 4];
         let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
 
-        for i in 0..100 {
-            let res = q.submit(
-                QueueEntry::new(i as u32, i * 10),
+        for i in 0..8 {
+            let res = q.submit_as(
+                (i % 2) as u8 + 1,
+                QueueEntry::new(i, i as i32),
                 wait,
                 wake,
                 SubmissionFlags::empty(),
             );
             assert_eq!(res, Ok(()));
-            let res = q.receive(wait, wake, ReceiveFlags::empty());
-            assert!(res.is_ok());
-            assert_eq!(res.unwrap().info(), i as u32);
-            assert_eq!(res.unwrap().item(), i * 10);
         }
+        let mut counts = [0; 3];
+        for i in 0..8 {
+            let res = q.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            assert_eq!(res.untagged_info(), i);
+            assert_eq!(res.producer_id(), (i % 2) as u8 + 1);
+            counts[res.producer_id() as usize] += 1;
+        }
+        assert_eq!(counts, [0, 4, 4]);
     }
 
     #[test]
-    fn it_fills() {
-        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+    fn it_wraps_counters() {
+        // Start just shy of the point where head and tail wrap around the 31-bit counter space
+        // (and the bell wraps around a 32-bit counter).
+        let qh = RawQueueHdr::with_counters(
+            2,
+            std::mem::size_of::<QueueEntry<u32>>(),
+            0xfffffff8,
+            0xfffffff8 as CounterValue,
+        );
         let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
         let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
 
-        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q.submit(QueueEntry::new(3, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q.submit(QueueEntry::new(4, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q.submit(
-            QueueEntry::new(1, 7),
-            wait,
-            wake,
-            SubmissionFlags::NON_BLOCK,
-        );
-        assert_eq!(res, Err(QueueError::WouldBlock));
+        for i in 0..8 {
+            for j in 0..4 {
+                let res = q.submit(
+                    QueueEntry::new(i * 4 + j, 7),
+                    wait,
+                    wake,
+                    SubmissionFlags::empty(),
+                );
+                assert_eq!(res, Ok(()));
+            }
+            for j in 0..4 {
+                let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+                assert_eq!(res.unwrap().info(), i * 4 + j);
+            }
+        }
     }
 
     #[test]
-    fn it_nonblock_receives() {
-        let qh = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
-        let mut buffer = [QueueEntry::<i32>::default(); 1 << 4];
-        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+    fn it_wraps_with_partial_occupancy() {
+        // Keep the queue partly full as the counters wrap, so that the head and tail spend a while
+        // on opposite sides of the wrap point.
+        for start in [0x7ffffff0, 0xfffffff0] {
+            let qh = RawQueueHdr::with_counters(
+                2,
+                std::mem::size_of::<QueueEntry<u32>>(),
+                start,
+                start as CounterValue,
+            );
+            let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+            let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
 
-        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q.receive(wait, wake, ReceiveFlags::empty());
-        assert!(res.is_ok());
-        assert_eq!(res.unwrap().info(), 1);
-        assert_eq!(res.unwrap().item(), 7);
-        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
-        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+            let (mut sent, mut received) = (0, 0);
+            for _ in 0..3 {
+                let res = q.submit(
+                    QueueEntry::new(sent, 7),
+                    wait,
+                    wake,
+                    SubmissionFlags::empty(),
+                );
+                assert_eq!(res, Ok(()));
+                sent += 1;
+            }
+            while sent < 64 {
+                let res = q.submit(
+                    QueueEntry::new(sent, 7),
+                    wait,
+                    wake,
+                    SubmissionFlags::empty(),
+                );
+                assert_eq!(res, Ok(()));
+                sent += 1;
+                let tail = qh.tail.load(Ordering::SeqCst);
+                assert!(qh.is_full(qh.head.load(Ordering::SeqCst), tail));
+                let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+                assert_eq!(res.unwrap().info(), received);
+                received += 1;
+            }
+            while let Ok(res) = q.receive(wait, wake, ReceiveFlags::NON_BLOCK) {
+                assert_eq!(res.info(), received);
+                received += 1;
+            }
+            assert_eq!(received, sent);
+        }
     }
 
     #[test]
-    fn it_multi_receives() {
-        let qh1 = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
-        let mut buffer1 = [QueueEntry::<i32>::default(); 1 << 4];
-        let q1 = unsafe { RawQueue::new(&qh1, buffer1.as_mut_ptr()) };
+    fn it_computes_fullness_across_wrap() {
+        // Check the counter arithmetic for every occupancy at every position around the wrap
+        // point, including with the consumer's waiting bit set in the tail.
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let len = qh.len() as u32;
+        for t in (0x7fffffff - 2 * len)..=(0x7fffffff + 2 * len) {
+            for used in 0..=len {
+                let h = t.wrapping_add(used);
+                for tail in [t as CounterValue, t as CounterValue | (1 << 31)] {
+                    assert_eq!(qh.is_full(h, tail), used == len);
+                    assert_eq!(qh.is_empty(h as CounterValue, tail), used == 0);
+                }
+            }
+        }
+    }
 
-        let qh2 = RawQueueHdr::new(4, std::mem::size_of::<QueueEntry<u32>>());
-        let mut buffer2 = [QueueEntry::<i32>::default(); 1 << 4];
-        let q2 = unsafe { RawQueue::new(&qh2, buffer2.as_mut_ptr()) };
+    #[test]
+    fn it_advances_tail_with_waiting_bit_at_wrap() {
+        let qh = RawQueueHdr::with_counters(
+            2,
+            std::mem::size_of::<QueueEntry<u32>>(),
+            0x7fffffff,
+            0x7fffffff,
+        );
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
 
-        let res = q1.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
-        assert_eq!(res, Ok(()));
-        let res = q2.submit(QueueEntry::new(2, 8), wait, wake, SubmissionFlags::empty());
+        let res = q.submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
         assert_eq!(res, Ok(()));
-
-        let mut output = [None, None];
-        let res = multi_receive(
-            &[&q1, &q2],
-            &mut output,
-            |_| {},
-            |_| {},
-            ReceiveFlags::empty(),
-        );
-        assert_eq!(res, Ok(2));
-        assert_eq!(output[0].unwrap().info(), 1);
-        assert_eq!(output[0].unwrap().item(), 7);
-        assert_eq!(output[1].unwrap().info(), 2);
-        assert_eq!(output[1].unwrap().item(), 8);
+        // The tail word is now all ones, so advancing it must wrap rather than overflow.
+        qh.consumer_set_waiting(true);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap().info(), 1);
+        assert_eq!(qh.tail.load(Ordering::SeqCst), 0);
     }
 
     /*
@@ -730,3 +6436,129 @@ mod tests {
         }
     */
 }
+
+// Model-checked tests of the queue protocol. Build and run them with
+// `RUSTFLAGS="--cfg loom" cargo test --release loom_tests`, both with and without the
+// weak-ordering feature. Loom runs each test under every interleaving of its threads' atomic
+// accesses, including those to the header's counters and to each entry's cmd_slot, and under
+// weak-ordering also every value that a load may see under the weaker orderings.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use crate::{
+        Counter, CounterValue, QueueEntry, RawQueue, RawQueueHdr, ReceiveFlags, SubmissionFlags,
+    };
+
+    // A queue, along with the header and buffer it points to, to share between threads.
+    struct Queue {
+        queue: RawQueue<u32>,
+        _hdr: Box<RawQueueHdr>,
+        _buf: Vec<QueueEntry<u32>>,
+    }
+
+    impl Queue {
+        fn new(l2len: usize) -> Arc<Self> {
+            // Call the wait callback straight away instead of spinning, so that loom doesn't
+            // explore every spin.
+            let stride = core::mem::size_of::<QueueEntry<u32>>();
+            let hdr = Box::new(RawQueueHdr::with_spin_limit(l2len, stride, 0));
+            let mut buf: Vec<_> = (0..1 << l2len).map(|_| QueueEntry::default()).collect();
+            let queue = unsafe { RawQueue::new(&*hdr, buf.as_mut_ptr()) };
+            Arc::new(Self {
+                queue,
+                _hdr: hdr,
+                _buf: buf,
+            })
+        }
+    }
+
+    // Waiting yields to loom, so that it runs the other threads.
+    fn wait(x: &Counter, v: CounterValue) {
+        while x.load(Ordering::SeqCst) == v {
+            thread::yield_now();
+        }
+    }
+
+    fn wake(_x: &Counter) {}
+
+    #[test]
+    fn it_publishes_and_consumes() {
+        loom::model(|| {
+            let q = Queue::new(1);
+            let producer = {
+                let q = q.clone();
+                thread::spawn(move || {
+                    let res =
+                        q.queue
+                            .submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                })
+            };
+            let item = q.queue.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            assert_eq!(item.info_and_item(), (1, 7));
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn it_publishes_after_writes() {
+        // A relaxed store made before submitting must be visible to the consumer once it has
+        // received the entry. This is the edge between the Release store that publishes an entry
+        // and the Acquire load that observes it.
+        loom::model(|| {
+            let q = Queue::new(1);
+            let written = Arc::new(AtomicU32::new(0));
+            let producer = {
+                let q = q.clone();
+                let written = written.clone();
+                thread::spawn(move || {
+                    written.store(7, Ordering::Relaxed);
+                    let res =
+                        q.queue
+                            .submit(QueueEntry::new(1, 7), wait, wake, SubmissionFlags::empty());
+                    assert_eq!(res, Ok(()));
+                })
+            };
+            let item = q.queue.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+            assert_eq!(item.info(), 1);
+            assert_eq!(written.load(Ordering::Relaxed), 7);
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn it_wraps() {
+        // The third entry goes in the first slot, on the next turn, so the producer has to wait
+        // for the consumer to free it, and the consumer mustn't take the first entry's turn bit
+        // for the third's.
+        loom::model(|| {
+            let q = Queue::new(1);
+            let producer = {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..3 {
+                        let res = q.queue.submit(
+                            QueueEntry::new(i, i),
+                            wait,
+                            wake,
+                            SubmissionFlags::empty(),
+                        );
+                        assert_eq!(res, Ok(()));
+                    }
+                })
+            };
+            for i in 0..3 {
+                let item = q.queue.receive(wait, wake, ReceiveFlags::empty()).unwrap();
+                assert_eq!(item.info_and_item(), (i, i));
+            }
+            producer.join().unwrap();
+        });
+    }
+}
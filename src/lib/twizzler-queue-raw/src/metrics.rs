@@ -0,0 +1,230 @@
+//! Instrumentation of how long queue operations spin before they get what they're waiting for,
+//! and how often they give up spinning and park, for tuning the spin limit.
+
+use core::sync::atomic::Ordering;
+
+use crate::{
+    Backoff, Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, ReceiveFlags,
+    SubmissionFlags,
+};
+
+/// The number of buckets in a spin time histogram. See [SpinStats::histogram].
+pub const SPIN_BUCKETS: usize = 32;
+
+/// An accumulator of spin and park counts for submissions and receives, which callers attach to
+/// the operations they want measured. See [RawQueue::submit_measured] and
+/// [RawQueue::receive_measured]. Any number of threads may share one.
+#[derive(Default)]
+pub struct OperationStats {
+    submits: SpinCounters,
+    receives: SpinCounters,
+}
+
+/// A snapshot of the spin and park counts for one kind of operation. See [OperationStats].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpinStats {
+    /// The number of operations measured.
+    pub operations: CounterValue,
+    /// The number of those operations that stopped spinning and called the wait callback.
+    pub parked: CounterValue,
+    /// The total time spent spinning, in ticks of the caller's clock.
+    pub spin_ticks: CounterValue,
+    /// A histogram of how long each operation spun. Bucket 0 counts operations that didn't spin
+    /// at all (or spun for less than a tick), and bucket i counts those that spun for at least
+    /// 2^(i-1) and less than 2^i ticks. The last bucket also counts anything longer.
+    pub histogram: [CounterValue; SPIN_BUCKETS],
+}
+
+#[derive(Default)]
+struct SpinCounters {
+    operations: Counter,
+    parked: Counter,
+    spin_ticks: Counter,
+    histogram: [Counter; SPIN_BUCKETS],
+}
+
+impl SpinCounters {
+    #[allow(clippy::unnecessary_cast)] // not unnecessary with narrow-counters
+    fn record(&self, ticks: u64, parked: bool) {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        if parked {
+            self.parked.fetch_add(1, Ordering::Relaxed);
+        }
+        self.spin_ticks
+            .fetch_add(ticks as CounterValue, Ordering::Relaxed);
+        let bucket = (u64::BITS - ticks.leading_zeros()) as usize;
+        self.histogram[bucket.min(SPIN_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> SpinStats {
+        SpinStats {
+            operations: self.operations.load(Ordering::Relaxed),
+            parked: self.parked.load(Ordering::Relaxed),
+            spin_ticks: self.spin_ticks.load(Ordering::Relaxed),
+            histogram: core::array::from_fn(|i| self.histogram[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl OperationStats {
+    /// Construct a new accumulator, with every count at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the counts for submissions.
+    pub fn submits(&self) -> SpinStats {
+        self.submits.snapshot()
+    }
+
+    /// Read the counts for receives.
+    pub fn receives(&self) -> SpinStats {
+        self.receives.snapshot()
+    }
+}
+
+// A backoff that measures another. Spinning is timed from the first spin until the inner backoff
+// gives up (or the operation finishes without it giving up), so operations that find what they're
+// waiting for straight away never read the clock. The first wait is what's measured: operations
+// that start over (a receive that skips an abandoned entry) keep their first start and end.
+struct Measured<'a, B, N> {
+    inner: B,
+    now: &'a N,
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+impl<'a, B: Backoff, N: Fn() -> u64> Measured<'a, B, N> {
+    fn new(inner: B, now: &'a N) -> Self {
+        Self {
+            inner,
+            now,
+            start: None,
+            end: None,
+        }
+    }
+
+    fn finish(&self, counters: &SpinCounters) {
+        let ticks = match self.start {
+            Some(start) => self.end.unwrap_or_else(self.now).wrapping_sub(start),
+            None => 0,
+        };
+        counters.record(ticks, self.end.is_some());
+    }
+}
+
+impl<'a, B: Backoff, N: Fn() -> u64> Backoff for Measured<'a, B, N> {
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn spin(&mut self) -> bool {
+        if self.start.is_none() {
+            self.start = Some((self.now)());
+        }
+        let spin = self.inner.spin();
+        if !spin && self.end.is_none() {
+            self.end = Some((self.now)());
+        }
+        spin
+    }
+}
+
+impl<T: EntryData> RawQueue<T> {
+    /// Submit a data item, like [RawQueue::submit], and record how long the submitter spun waiting
+    /// for room, and whether it parked, in `stats`. The `now` callback reads a clock, in whatever
+    /// ticks the caller likes, and is only called if the submitter has to spin.
+    pub fn submit_measured<W: Fn(&Counter, CounterValue), R: Fn(&Counter), N: Fn() -> u64>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+        stats: &OperationStats,
+        now: N,
+    ) -> Result<(), QueueError> {
+        let mut backoff = Measured::new(self.hdr().backoff(), &now);
+        let res = self.submit_with_backoff(item, wait, ring, &mut backoff, flags);
+        backoff.finish(&stats.submits);
+        res
+    }
+
+    /// Receive data from the queue, like [RawQueue::receive], and record how long the consumer
+    /// spun waiting for an entry, and whether it parked, in `stats`. See
+    /// [RawQueue::submit_measured].
+    pub fn receive_measured<W: Fn(&Counter, CounterValue), R: Fn(&Counter), N: Fn() -> u64>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+        stats: &OperationStats,
+        now: N,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        let mut backoff = Measured::new(self.hdr().backoff(), &now);
+        let res = self.receive_with_backoff(wait, ring, &mut backoff, flags);
+        backoff.finish(&stats.receives);
+        res
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::OperationStats;
+    use crate::{
+        spin_wait as wait, spin_wake as wake, QueueEntry, QueueError, RawQueue, RawQueueHdr,
+        ReceiveFlags, SubmissionFlags,
+    };
+
+    #[test]
+    fn it_measures_spins() {
+        let qh = RawQueueHdr::with_spin_limit(2, std::mem::size_of::<QueueEntry<u32>>(), 4);
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let stats = OperationStats::new();
+        // A clock that ticks on every read.
+        let clock = AtomicU64::new(0);
+        let now = || clock.fetch_add(1, Ordering::Relaxed);
+
+        let res = q.submit_measured(
+            QueueEntry::new(1, 7),
+            wait,
+            wake,
+            SubmissionFlags::empty(),
+            &stats,
+            now,
+        );
+        assert_eq!(res, Ok(()));
+        let res = q.receive_measured(wait, wake, ReceiveFlags::empty(), &stats, now);
+        assert_eq!(res.unwrap().info(), 1);
+        // Neither had to spin, so neither read the clock.
+        assert_eq!(clock.load(Ordering::Relaxed), 0);
+
+        let res = q.receive_measured(wait, wake, ReceiveFlags::NON_BLOCK, &stats, now);
+        assert_eq!(res, Err(QueueError::WouldBlock));
+
+        // Once the consumer gives up spinning, the submission it's parked waiting for wakes it.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !qh.consumer_waiting() {
+                    core::hint::spin_loop();
+                }
+                let res = q.submit(QueueEntry::new(2, 7), wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(()));
+            });
+            let res = q.receive_measured(wait, wake, ReceiveFlags::empty(), &stats, now);
+            assert_eq!(res.unwrap().info(), 2);
+        });
+
+        let submits = stats.submits();
+        assert_eq!((submits.operations, submits.parked), (1, 0));
+        assert_eq!(submits.histogram[0], 1);
+        let receives = stats.receives();
+        assert_eq!((receives.operations, receives.parked), (3, 1));
+        // The clock was read once when the consumer started spinning, and once when it parked.
+        assert_eq!(receives.spin_ticks, 1);
+        assert_eq!(receives.histogram[0], 2);
+        assert_eq!(receives.histogram[1], 1);
+    }
+}
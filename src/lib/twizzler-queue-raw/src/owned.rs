@@ -0,0 +1,271 @@
+//! A queue that allocates and owns its header and buffer, for channels within a single process.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    EntryData, QueueEntry, QueueError, QueueLayoutError, QueueWaiter, RawQueue, RawQueueHdr,
+    ReceiveFlags, StdWaiter, SubmissionFlags,
+};
+
+/// A queue that allocates its own header and buffer on the heap, and waits with a [StdWaiter], for
+/// when the queue is just a channel between threads and doesn't need to live in shared memory.
+/// Submitting and receiving don't need wait and ring callbacks, and the rest of the [RawQueue]
+/// methods are available through deref.
+///
+/// When the queue is dropped, any entries still in it are dropped too. The exception is a slot that
+/// a submitter reserved but never published (if its backoff panicked while it waited for room,
+/// say): the entry may be only partly written, so it can't be dropped, and it's leaked instead,
+/// along with any entries behind it. See [OwnedQueue::split] for a pair of handles that enforce
+/// the single consumer.
+pub struct OwnedQueue<T: EntryData> {
+    // The queue points at a boxed header and buffer, which are freed on drop.
+    queue: RawQueue<T>,
+    waiter: StdWaiter,
+}
+
+impl<T: EntryData> OwnedQueue<T> {
+    /// Allocate a new queue of 2^l2len entries. Fails if the queue would be too long (see
+    /// [RawQueueHdr::new_checked]).
+    pub fn new(l2len: usize) -> Result<Self, QueueLayoutError> {
+        let hdr = RawQueueHdr::new_checked::<T>(l2len, core::mem::size_of::<QueueEntry<T>>())?;
+        let buf: Box<[MaybeUninit<QueueEntry<T>>]> =
+            (0..hdr.capacity()).map(|_| MaybeUninit::uninit()).collect();
+        let buf = Box::into_raw(buf);
+        let hdr = Box::into_raw(Box::new(hdr));
+        Ok(Self {
+            queue: unsafe { RawQueue::new(hdr, RawQueue::init_buffer(&mut *buf)) },
+            waiter: StdWaiter::new(),
+        })
+    }
+
+    /// Submit a data item to the queue. See [RawQueue::submit].
+    pub fn submit(&self, item: QueueEntry<T>, flags: SubmissionFlags) -> Result<(), QueueError> {
+        self.queue.submit(
+            item,
+            |w, v| self.waiter.wait(w, v),
+            |w| self.waiter.wake(w),
+            flags,
+        )
+    }
+
+    /// Receive data from the queue. See [RawQueue::receive].
+    pub fn receive(&self, flags: ReceiveFlags) -> Result<QueueEntry<T>, QueueError> {
+        self.queue.receive(
+            |w, v| self.waiter.wait(w, v),
+            |w| self.waiter.wake(w),
+            flags,
+        )
+    }
+
+    /// Close the queue, waking anyone waiting on it. See [RawQueueHdr::close].
+    pub fn close(&self) {
+        self.queue.hdr().close(|w| self.waiter.wake(w));
+    }
+
+    /// Split the queue into a handle for submitting, which can be cloned to give one to each
+    /// producer, and a handle for receiving, of which there is only one. This makes the single
+    /// consumer a property of the types rather than something the callers have to agree on.
+    ///
+    /// Like a std channel, the queue is closed once the receiver or every sender has been dropped,
+    /// so that the other side gets Err([QueueError::Closed]) instead of waiting forever. The
+    /// receiver still gets any entries that were submitted before the senders went away.
+    pub fn split(self) -> (Sender<T>, Receiver<T>) {
+        let shared = Arc::new(Shared {
+            queue: self,
+            senders: AtomicUsize::new(1),
+        });
+        (
+            Sender {
+                shared: shared.clone(),
+            },
+            Receiver {
+                shared,
+                _not_sync: PhantomData,
+            },
+        )
+    }
+}
+
+impl<T: EntryData> Drop for OwnedQueue<T> {
+    fn drop(&mut self) {
+        self.queue.drain(|_| {}).for_each(drop);
+        unsafe {
+            let len = self.queue.capacity();
+            drop(Box::from_raw(core::ptr::slice_from_raw_parts_mut(
+                self.queue.buf.cast::<MaybeUninit<QueueEntry<T>>>(),
+                len,
+            )));
+            drop(Box::from_raw(self.queue.hdr.cast_mut()));
+        }
+    }
+}
+
+impl<T: EntryData> core::ops::Deref for OwnedQueue<T> {
+    type Target = RawQueue<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+struct Shared<T: EntryData> {
+    queue: OwnedQueue<T>,
+    senders: AtomicUsize,
+}
+
+/// The submitting half of a split [OwnedQueue]. Clone it to give a handle to each producer.
+pub struct Sender<T: EntryData> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: EntryData> Sender<T> {
+    /// Submit a data item to the queue. See [RawQueue::submit].
+    pub fn submit(&self, item: QueueEntry<T>, flags: SubmissionFlags) -> Result<(), QueueError> {
+        self.shared.queue.submit(item, flags)
+    }
+}
+
+impl<T: EntryData> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: EntryData> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.queue.close();
+        }
+    }
+}
+
+/// The receiving half of a split [OwnedQueue]. It can be sent to another thread, but not cloned
+/// or shared, so only one thread can ever receive.
+pub struct Receiver<T: EntryData> {
+    shared: Arc<Shared<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T: EntryData> Receiver<T> {
+    /// Receive data from the queue. See [RawQueue::receive].
+    pub fn receive(&self, flags: ReceiveFlags) -> Result<QueueEntry<T>, QueueError> {
+        self.shared.queue.receive(flags)
+    }
+
+    /// Close the queue, and receive everything left in it, for callers that want to look at the
+    /// leftovers rather than have them dropped along with the queue. Submissions that were still
+    /// in progress when the queue closed may not be included; their entries are dropped with the
+    /// queue, as usual.
+    pub fn close_and_drain(&self) -> Vec<QueueEntry<T>> {
+        let queue = &self.shared.queue;
+        queue.close();
+        queue.drain(|w| queue.waiter.wake(w)).collect()
+    }
+}
+
+impl<T: EntryData> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.queue.close();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    #[cfg(not(feature = "checksum"))]
+    use crate::test_util::Drops;
+    use crate::{Info, OwnedQueue, QueueEntry, QueueError, ReceiveFlags, SubmissionFlags};
+
+    #[test]
+    fn it_owns_its_buffer() {
+        let q = OwnedQueue::new(2).unwrap();
+        assert_eq!(q.capacity(), 4);
+        for i in 0..10 {
+            assert_eq!(
+                q.submit(QueueEntry::new(i, 7), SubmissionFlags::empty()),
+                Ok(())
+            );
+            assert_eq!(q.receive(ReceiveFlags::empty()).unwrap().info(), i);
+        }
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    #[test]
+    fn it_drops_leftovers() {
+        // Entries left in the queue are dropped along with it.
+        let drops = Drops::default();
+        let q = OwnedQueue::new(2).unwrap();
+        for i in 0..3 {
+            let item = QueueEntry::new(i, drops.payload());
+            assert!(q.submit(item, SubmissionFlags::empty()).is_ok());
+        }
+        drop(q.receive(ReceiveFlags::empty()));
+        assert_eq!(drops.count(), 1);
+        drop(q);
+        assert_eq!(drops.count(), 3);
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    #[test]
+    fn it_drains_on_close() {
+        let (tx, rx) = OwnedQueue::new(2).unwrap().split();
+        for i in 0..3 {
+            let res = tx.submit(QueueEntry::new(i, vec![i]), SubmissionFlags::empty());
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(rx.receive(ReceiveFlags::empty()).unwrap().info(), 0);
+        let left = rx.close_and_drain();
+        assert_eq!(
+            left,
+            [QueueEntry::new(1, vec![1]), QueueEntry::new(2, vec![2])]
+        );
+        let res = tx.submit(QueueEntry::new(3, vec![3]), SubmissionFlags::empty());
+        assert_eq!(res, Err(QueueError::Closed));
+        assert_eq!(rx.receive(ReceiveFlags::empty()), Err(QueueError::Closed));
+    }
+
+    #[test]
+    fn it_splits() {
+        let (tx, rx) = OwnedQueue::new(2).unwrap().split();
+        std::thread::scope(|s| {
+            for p in 0..4 as Info {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let res =
+                            tx.submit(QueueEntry::new(p * 100 + i, 7), SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+            }
+            drop(tx);
+            let mut seen = Vec::new();
+            loop {
+                match rx.receive(ReceiveFlags::empty()) {
+                    Ok(item) => seen.push(item.info()),
+                    Err(e) => {
+                        assert_eq!(e, QueueError::Closed);
+                        break;
+                    }
+                }
+            }
+            seen.sort();
+            assert_eq!(seen, (0..400).collect::<Vec<_>>());
+        });
+
+        let (tx, rx) = OwnedQueue::<u32>::new(2).unwrap().split();
+        drop(rx);
+        let res = tx.submit(QueueEntry::new(1, 7), SubmissionFlags::empty());
+        assert_eq!(res, Err(QueueError::Closed));
+    }
+}
@@ -0,0 +1,265 @@
+//! A queue with two priority lanes, built out of a pair of raw queues.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, ReceiveFlags,
+    SubmissionFlags,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// The lane to submit an entry to in a [PriorityRawQueue].
+pub enum Priority {
+    /// Entries that are received before any low priority entries.
+    High,
+    /// Entries that are only received once the high priority lane is empty.
+    Low,
+}
+
+/// A queue with a high and a low priority lane, each of which is a [RawQueue]. The consumer
+/// always takes entries from the high lane first, and only falls back to the low lane when the
+/// high lane is empty. Entries within a lane are received in order, but a high priority entry may
+/// overtake low priority entries submitted before it.
+///
+/// The consumer can't wait on both lanes' bells at once, so it waits on a bell of its own, which
+/// submitters bump after submitting to either lane (and ring if the consumer is waiting). The
+/// lanes' own bells are never waited on. Since that bell lives in this struct, every producer and
+/// the consumer must share the same PriorityRawQueue, and the lanes must not be used directly.
+pub struct PriorityRawQueue<T> {
+    high: RawQueue<T>,
+    low: RawQueue<T>,
+    bell: Counter,
+    waiting: AtomicBool,
+}
+
+impl<T: EntryData> PriorityRawQueue<T> {
+    /// Construct a new priority queue out of a queue for each lane.
+    pub fn new(high: RawQueue<T>, low: RawQueue<T>) -> Self {
+        Self {
+            high,
+            low,
+            bell: Counter::new(0),
+            waiting: AtomicBool::new(false),
+        }
+    }
+
+    fn lane(&self, priority: Priority) -> &RawQueue<T> {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        }
+    }
+
+    /// Submit a data item to the lane for the given priority. The callbacks and flags work as in
+    /// [RawQueue::submit]: if the lane is full, this waits on that lane, and once the item is
+    /// submitted, ring is called if the consumer needs waking.
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        priority: Priority,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.lane(priority).submit(item, wait, &ring, flags)?;
+        // This must be SeqCst, and come after the submission, to pair with the consumer setting
+        // the waiting flag and then checking the lanes.
+        self.bell.fetch_add(1, Ordering::SeqCst);
+        if self.waiting.load(Ordering::SeqCst) {
+            ring(&self.bell);
+        }
+        Ok(())
+    }
+
+    /// Close both lanes, waking the consumer and any submitters waiting on them. See
+    /// [crate::RawQueueHdr::close]. The lanes should be closed through this rather than
+    /// directly, since the consumer waits on the bell in this struct, which closing a lane alone
+    /// doesn't change.
+    pub fn close<R: Fn(&Counter)>(&self, ring: R) {
+        self.high.hdr().close(&ring);
+        self.low.hdr().close(&ring);
+        // As with a lane's own close, change the word the consumer waits on, as well as ringing
+        // it, so that a consumer that is just about to wait doesn't miss it.
+        self.bell.fetch_add(1, Ordering::SeqCst);
+        ring(&self.bell);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.high.hdr().is_closed() && self.low.hdr().is_closed()
+    }
+
+    // Receive from the high lane, and then from the low lane, without blocking. A closed lane is
+    // just one with nothing more to give, so the queue is only closed once both lanes are.
+    fn try_lanes<R: Fn(&Counter)>(&self, ring: R) -> Result<QueueEntry<T>, QueueError> {
+        let high = match self.high.receive(|_, _| {}, &ring, ReceiveFlags::NON_BLOCK) {
+            Err(e @ (QueueError::WouldBlock | QueueError::Closed)) => e,
+            res => return res,
+        };
+        match self.low.receive(|_, _| {}, &ring, ReceiveFlags::NON_BLOCK) {
+            Err(QueueError::Closed) if high == QueueError::WouldBlock => {
+                Err(QueueError::WouldBlock)
+            }
+            res => res,
+        }
+    }
+
+    /// Receive data from the queue, from the high priority lane if it has any, and otherwise from
+    /// the low priority lane. If both lanes are empty, this waits (unless NON_BLOCK is specified)
+    /// until either one has an entry. The callbacks work as in [RawQueue::receive]. A lane that has
+    /// been closed is treated as empty, and Err([QueueError::Closed]) is only returned once both
+    /// lanes are closed and drained.
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        loop {
+            match self.try_lanes(&ring) {
+                Err(QueueError::WouldBlock) => {}
+                res => return res,
+            }
+
+            if flags.contains(ReceiveFlags::NON_BLOCK) {
+                return Err(QueueError::WouldBlock);
+            }
+
+            self.waiting.store(true, Ordering::SeqCst);
+            let b = self.bell.load(Ordering::SeqCst);
+            // The lanes are closed before the bell is bumped, so if b is already past the bump,
+            // this sees them closed.
+            if self.high.is_empty() && self.low.is_empty() && !self.is_closed() {
+                wait(&self.bell, b);
+            }
+            self.waiting.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use core::sync::atomic::Ordering;
+
+    use crate::{
+        spin_wait as wait, spin_wake as wake, Info, Priority, PriorityRawQueue, QueueEntry,
+        QueueError, RawQueue, RawQueueHdr, ReceiveFlags, SubmissionFlags,
+    };
+
+    #[test]
+    fn it_prioritizes() {
+        let hh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let lh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut hbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let mut lbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe {
+            PriorityRawQueue::new(
+                RawQueue::new(&hh, hbuf.as_mut_ptr()),
+                RawQueue::new(&lh, lbuf.as_mut_ptr()),
+            )
+        };
+
+        for (i, priority) in [Priority::Low, Priority::High, Priority::Low, Priority::High]
+            .into_iter()
+            .enumerate()
+        {
+            let res = q.submit(
+                QueueEntry::new(i as Info, 7),
+                priority,
+                wait,
+                wake,
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+        }
+        for i in [1, 3, 0, 2] {
+            let res = q.receive(wait, wake, ReceiveFlags::empty());
+            assert_eq!(res.unwrap().info(), i);
+        }
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+
+        // A waiting consumer is woken by either lane.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    let priority = if i % 3 == 0 {
+                        Priority::High
+                    } else {
+                        Priority::Low
+                    };
+                    let res = q.submit(
+                        QueueEntry::new(i, 7),
+                        priority,
+                        wait,
+                        wake,
+                        SubmissionFlags::empty(),
+                    );
+                    assert_eq!(res, Ok(()));
+                }
+            });
+            let mut received = Vec::new();
+            for _ in 0..100 {
+                let res = q.receive(wait, wake, ReceiveFlags::empty());
+                received.push(res.unwrap().info());
+            }
+            received.sort();
+            assert_eq!(received, (0..100).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn it_closes_when_both_lanes_close() {
+        let hh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let lh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut hbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let mut lbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe {
+            PriorityRawQueue::new(
+                RawQueue::new(&hh, hbuf.as_mut_ptr()),
+                RawQueue::new(&lh, lbuf.as_mut_ptr()),
+            )
+        };
+
+        let res = q.submit(
+            QueueEntry::new(1, 7),
+            Priority::Low,
+            wait,
+            wake,
+            SubmissionFlags::empty(),
+        );
+        assert_eq!(res, Ok(()));
+        // A closed high lane doesn't hide the low lane, or stop it from being waited on.
+        hh.close(wake);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 1);
+        let res = q.receive(wait, wake, ReceiveFlags::NON_BLOCK);
+        assert_eq!(res.unwrap_err(), QueueError::WouldBlock);
+
+        lh.close(wake);
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap_err(), QueueError::Closed);
+    }
+
+    #[test]
+    fn it_wakes_a_parked_consumer_on_close() {
+        let hh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let lh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut hbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let mut lbuf = [QueueEntry::<u32>::default(); 1 << 2];
+        let q = unsafe {
+            PriorityRawQueue::new(
+                RawQueue::new(&hh, hbuf.as_mut_ptr()),
+                RawQueue::new(&lh, lbuf.as_mut_ptr()),
+            )
+        };
+
+        std::thread::scope(|s| {
+            let consumer = s.spawn(|| q.receive(wait, wake, ReceiveFlags::empty()).unwrap_err());
+            while !q.waiting.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+            q.close(wake);
+            assert_eq!(consumer.join().unwrap(), QueueError::Closed);
+        });
+    }
+}
@@ -0,0 +1,111 @@
+//! Submitting and receiving handles for a raw queue, which enforce the single consumer.
+
+use core::{cell::Cell, marker::PhantomData};
+
+use crate::{
+    Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, ReceiveFlags,
+    SubmissionFlags,
+};
+
+/// The submitting half of a split [RawQueue]. See [RawQueue::split].
+pub struct RawSender<'a, T> {
+    queue: &'a RawQueue<T>,
+}
+
+/// The receiving half of a split [RawQueue]. See [RawQueue::split].
+pub struct RawReceiver<'a, T> {
+    queue: &'a RawQueue<T>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T: EntryData> RawQueue<T> {
+    /// Split the queue into a handle for submitting and a handle for receiving. The sender can be
+    /// copied and sent to any number of producer threads, while there's only ever one receiver,
+    /// which can be sent to another thread but not shared, so the single consumer is enforced by
+    /// the types instead of being up to the callers. Borrowing the queue mutably for as long as
+    /// the handles live keeps anyone from receiving from the queue directly in the meantime.
+    pub fn split(&mut self) -> (RawSender<'_, T>, RawReceiver<'_, T>) {
+        let queue = &*self;
+        (
+            RawSender { queue },
+            RawReceiver {
+                queue,
+                _not_sync: PhantomData,
+            },
+        )
+    }
+}
+
+impl<'a, T: EntryData> RawSender<'a, T> {
+    /// Submit a data item to the queue. See [RawQueue::submit].
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        self.queue.submit(item, wait, ring, flags)
+    }
+}
+
+impl<'a, T> Clone for RawSender<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for RawSender<'a, T> {}
+
+impl<'a, T: EntryData> RawReceiver<'a, T> {
+    /// Receive data from the queue. See [RawQueue::receive].
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        self.queue.receive(wait, ring, flags)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{
+        spin_wait as wait, spin_wake as wake, Info, QueueEntry, RawQueue, RawQueueHdr,
+        ReceiveFlags, SubmissionFlags,
+    };
+
+    #[test]
+    fn it_splits() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<u32>::default(); 1 << 2];
+        let mut q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        let (tx, rx) = q.split();
+        std::thread::scope(|s| {
+            for p in 0..4 as Info {
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let item = QueueEntry::new(p * 100 + i, 7);
+                        assert_eq!(
+                            tx.submit(item, wait, wake, SubmissionFlags::empty()),
+                            Ok(())
+                        );
+                    }
+                });
+            }
+            let consumer = s.spawn(move || {
+                let mut seen = (0..400)
+                    .map(|_| {
+                        rx.receive(wait, wake, ReceiveFlags::empty())
+                            .unwrap()
+                            .info()
+                    })
+                    .collect::<Vec<_>>();
+                seen.sort();
+                seen
+            });
+            assert_eq!(consumer.join().unwrap(), (0..400).collect::<Vec<_>>());
+        });
+    }
+}
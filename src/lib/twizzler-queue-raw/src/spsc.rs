@@ -0,0 +1,325 @@
+//! A fast path for queues with exactly one producer and one consumer.
+
+use core::{cell::Cell, sync::atomic::Ordering};
+
+use crate::{
+    relax, Counter, CounterValue, EntryData, QueueEntry, QueueError, RawQueue, ReceiveFlags,
+    SubmissionFlags, COUNTER_MASK, ORD_OBSERVE, POSITION_MASK,
+};
+
+/// The producing half of a single-producer, single-consumer queue. See [RawQueue::split_spsc].
+pub struct SpscProducer<'a, T> {
+    queue: &'a RawQueue<T>,
+    // The producer is the only writer of the head and bell, so it keeps them locally, along with
+    // the last tail it saw, so that it only needs to look at the consumer's tail when the queue
+    // looks full.
+    head: Cell<u32>,
+    bell: Cell<CounterValue>,
+    tail: Cell<CounterValue>,
+}
+
+/// The consuming half of a single-producer, single-consumer queue. See [RawQueue::split_spsc].
+pub struct SpscConsumer<'a, T> {
+    queue: &'a RawQueue<T>,
+    // Likewise, the consumer keeps its tail locally, along with the last bell it saw.
+    tail: Cell<CounterValue>,
+    bell: Cell<CounterValue>,
+}
+
+impl<T: Copy + EntryData> RawQueue<T> {
+    /// Split the queue into a producer and a consumer, for queues with exactly one of each. This
+    /// is a faster path than the general [RawQueue::submit] and [RawQueue::receive]: the hot path
+    /// has no atomic read-modify-write operations at all, just loads and stores, and each side only
+    /// reads the other side's counter when the queue looks full (or empty) according to the last
+    /// value it saw.
+    ///
+    /// The queue must not be used by anyone else while it's split. Borrowing the queue mutably for
+    /// as long as the halves live keeps this handle from being used (or split again) in the
+    /// meantime, as with [RawQueue::split], but other handles to the same header and buffer, such
+    /// as those of other processes, must also keep out. Entries submitted by the producer are in
+    /// the usual format, though, so once the halves are dropped, the queue can go back to being
+    /// used as normal.
+    pub fn split_spsc(&mut self) -> (SpscProducer<'_, T>, SpscConsumer<'_, T>) {
+        let queue = &*self;
+        let hdr = queue.hdr();
+        let head = hdr.head.load(Ordering::SeqCst) & POSITION_MASK;
+        let bell = hdr.bell.load(Ordering::SeqCst);
+        let tail = hdr.tail.load(Ordering::SeqCst) & COUNTER_MASK;
+        (
+            SpscProducer {
+                queue,
+                head: Cell::new(head),
+                bell: Cell::new(bell),
+                tail: Cell::new(tail),
+            },
+            SpscConsumer {
+                queue,
+                tail: Cell::new(tail),
+                bell: Cell::new(bell),
+            },
+        )
+    }
+}
+
+impl<'a, T: Copy + EntryData> SpscProducer<'a, T> {
+    /// Submit a data item to the queue. The callbacks and flags work as in [RawQueue::submit].
+    pub fn submit<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        item: QueueEntry<T>,
+        wait: W,
+        ring: R,
+        flags: SubmissionFlags,
+    ) -> Result<(), QueueError> {
+        let hdr = self.queue.hdr();
+        if hdr.is_closed() {
+            return Err(QueueError::Closed);
+        }
+        let h = self.head.get();
+        if hdr.is_full(h, self.tail.get()) {
+            self.tail.set(self.wait_for_room(h, wait, flags)?);
+        }
+
+        let slot = self.queue.get_buf(h as usize);
+        slot.info = item.info;
+        slot.data = item.data;
+        slot.publish(hdr.cmd_slot(h, false));
+
+        let next = h.wrapping_add(1) & POSITION_MASK;
+        self.head.set(next);
+        hdr.head.store(next, Ordering::Relaxed);
+        let bell = self.bell.get().wrapping_add(1);
+        self.bell.set(bell);
+        // This store and the check of the consumer's waiting flag must be SeqCst, to pair with the
+        // consumer setting the flag and then checking the bell.
+        hdr.bell.store(bell, Ordering::SeqCst);
+        #[cfg(feature = "watermark")]
+        hdr.record_depth(bell);
+        if hdr.consumer_needs_wake() {
+            hdr.trace("waking consumer");
+            ring(&hdr.bell);
+        }
+        Ok(())
+    }
+
+    fn wait_for_room<W: Fn(&Counter, CounterValue)>(
+        &self,
+        h: u32,
+        wait: W,
+        flags: SubmissionFlags,
+    ) -> Result<CounterValue, QueueError> {
+        let hdr = self.queue.hdr();
+        let mut waiter = false;
+        let limit = hdr.spin_limit();
+        let mut attempts = limit;
+        let res = loop {
+            let t = hdr.tail.load(ORD_OBSERVE);
+            if !hdr.is_full(h, t) {
+                break Ok(t);
+            }
+
+            if flags.contains(SubmissionFlags::NON_BLOCK) {
+                break Err(QueueError::WouldBlock);
+            }
+
+            if hdr.is_closed() {
+                break Err(QueueError::Closed);
+            }
+
+            if attempts != 0 {
+                attempts -= 1;
+                relax(limit, attempts, &core::hint::spin_loop);
+                continue;
+            }
+
+            if !waiter {
+                waiter = true;
+                hdr.inc_submit_waiting();
+            }
+
+            let t = hdr.tail.load(Ordering::SeqCst);
+            if hdr.is_full(h, t) {
+                hdr.wait_tail(&wait, t);
+            }
+        };
+
+        if waiter {
+            hdr.dec_submit_waiting();
+        }
+        res
+    }
+}
+
+impl<'a, T: Copy + EntryData> SpscConsumer<'a, T> {
+    /// Receive data from the queue. The callbacks and flags work as in [RawQueue::receive].
+    pub fn receive<W: Fn(&Counter, CounterValue), R: Fn(&Counter)>(
+        &self,
+        wait: W,
+        ring: R,
+        flags: ReceiveFlags,
+    ) -> Result<QueueEntry<T>, QueueError> {
+        let hdr = self.queue.hdr();
+        let t = self.tail.get();
+        let slot = self.queue.get_buf(t as usize) as *const QueueEntry<T>;
+        if hdr.is_empty(self.bell.get(), t) || !hdr.is_turn(t, slot) {
+            self.bell.set(self.wait_for_entry(t, slot, wait, flags)?);
+        }
+
+        let item = unsafe { QueueEntry::read_from(self.queue.get_buf(t as usize)) };
+        self.queue.check_entry(t, &item)?;
+        let next = t.wrapping_add(1) & COUNTER_MASK;
+        self.tail.set(next);
+        // As in the producer, this store and the check of the submitter waiters must be SeqCst.
+        // Storing the tail also clears the waiting flag, if we set it.
+        hdr.tail.store(next, Ordering::SeqCst);
+        if hdr.submitter_waiting() {
+            ring(&hdr.tail);
+        }
+        Ok(item)
+    }
+
+    fn wait_for_entry<W: Fn(&Counter, CounterValue)>(
+        &self,
+        t: CounterValue,
+        slot: *const QueueEntry<T>,
+        wait: W,
+        flags: ReceiveFlags,
+    ) -> Result<CounterValue, QueueError> {
+        let hdr = self.queue.hdr();
+        let limit = hdr.spin_limit();
+        let mut attempts = limit;
+        loop {
+            let b = hdr.bell.load(ORD_OBSERVE);
+            if !hdr.is_empty(b, t) && hdr.is_turn(t, slot) {
+                return Ok(b);
+            }
+
+            if hdr.is_closed() {
+                return Err(QueueError::Closed);
+            }
+
+            if flags.contains(ReceiveFlags::NON_BLOCK) {
+                return Err(QueueError::WouldBlock);
+            }
+
+            if attempts != 0 {
+                attempts -= 1;
+                relax(limit, attempts, &core::hint::spin_loop);
+                continue;
+            }
+
+            hdr.consumer_set_waiting(true);
+            let b = hdr.bell.load(Ordering::SeqCst);
+            if hdr.is_empty(b, t) || !hdr.is_turn(t, slot) {
+                hdr.wait_bell(&wait, b);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    extern crate test;
+
+    use crate::{
+        spin_wait as wait, spin_wake as wake, Info, QueueEntry, QueueError, RawQueue, RawQueueHdr,
+        ReceiveFlags, SubmissionFlags,
+    };
+
+    #[test]
+    fn it_transmits_spsc() {
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let mut q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+
+        {
+            let (tx, rx) = q.split_spsc();
+            for i in 0..4 {
+                let res = tx.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                assert_eq!(res, Ok(()));
+            }
+            let res = tx.submit(
+                QueueEntry::new(4, 7),
+                wait,
+                wake,
+                SubmissionFlags::NON_BLOCK,
+            );
+            assert_eq!(res, Err(QueueError::WouldBlock));
+            for i in 0..2 {
+                let res = rx.receive(wait, wake, ReceiveFlags::empty());
+                assert_eq!(res.unwrap().info(), i);
+            }
+
+            std::thread::scope(|s| {
+                s.spawn(move || {
+                    for i in 4..200 {
+                        let res =
+                            tx.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+                for i in 2..199 {
+                    let res = rx.receive(wait, wake, ReceiveFlags::empty());
+                    assert_eq!(res.unwrap().info(), i);
+                }
+            });
+        }
+
+        // Once the halves are gone, the queue is back to normal.
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 199);
+        let res = q.submit(
+            QueueEntry::new(200, 7),
+            wait,
+            wake,
+            SubmissionFlags::empty(),
+        );
+        assert_eq!(res, Ok(()));
+        let res = q.receive(wait, wake, ReceiveFlags::empty());
+        assert_eq!(res.unwrap().info(), 200);
+    }
+
+    const BENCH_ITEMS: Info = 1 << 12;
+
+    #[bench]
+    fn two_threads_generic(b: &mut test::Bencher) {
+        let qh = RawQueueHdr::new(10, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = vec![QueueEntry::<i32>::default(); 1 << 10];
+        let q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        b.iter(|| {
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    for i in 0..BENCH_ITEMS {
+                        let res =
+                            q.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+                for _ in 0..BENCH_ITEMS {
+                    test::black_box(q.receive(wait, wake, ReceiveFlags::empty()).unwrap());
+                }
+            });
+        });
+    }
+
+    #[bench]
+    fn two_threads_spsc(b: &mut test::Bencher) {
+        let qh = RawQueueHdr::new(10, std::mem::size_of::<QueueEntry<u32>>());
+        let mut buffer = vec![QueueEntry::<i32>::default(); 1 << 10];
+        let mut q = unsafe { RawQueue::new(&qh, buffer.as_mut_ptr()) };
+        b.iter(|| {
+            let (tx, rx) = q.split_spsc();
+            std::thread::scope(|s| {
+                s.spawn(move || {
+                    for i in 0..BENCH_ITEMS {
+                        let res =
+                            tx.submit(QueueEntry::new(i, 7), wait, wake, SubmissionFlags::empty());
+                        assert_eq!(res, Ok(()));
+                    }
+                });
+                for _ in 0..BENCH_ITEMS {
+                    test::black_box(rx.receive(wait, wake, ReceiveFlags::empty()).unwrap());
+                }
+            });
+        });
+    }
+}
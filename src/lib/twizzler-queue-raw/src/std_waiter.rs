@@ -0,0 +1,117 @@
+//! A ready-made waiter for queues whose producers and consumer all live in one process.
+
+use std::sync::{Condvar, Mutex};
+
+use crate::{
+    Counter, CounterValue, EntryData, QueueEntry, QueueWaiter, RawQueue, RawQueueHdr, WaiterQueue,
+};
+
+/// A waiter built on std's blocking primitives, for queues that are only used within a single
+/// process. Use it with [RawQueue::with_waiter] (or [RawQueue::with_std_waiter]) to get submit and
+/// receive functions that don't need wait and ring callbacks.
+///
+/// A thread that waits blocks on a condition variable shared by every word of the queue, so a
+/// ring wakes everyone waiting on the queue, not just those waiting on that word. The queue
+/// rechecks its state after every wait, so the extra wakes only cost a little time. Like a futex
+/// wake, a ring wakes waiters even if the word hasn't changed, which [RawQueueHdr::close] relies
+/// on. Since the condition variable lives in this process, all the parties to the queue must share
+/// the same waiter.
+pub struct StdWaiter {
+    // Bumped on every ring, so that waiters can tell that they've been woken.
+    rings: Mutex<u64>,
+    cv: Condvar,
+}
+
+impl StdWaiter {
+    /// Construct a new waiter.
+    pub fn new() -> Self {
+        Self {
+            rings: Mutex::new(0),
+            cv: Condvar::new(),
+        }
+    }
+}
+
+impl Default for StdWaiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QueueWaiter for StdWaiter {
+    fn wait(&self, word: &Counter, expected: CounterValue) {
+        let rings = self.rings.lock().unwrap();
+        // Ringers change the word before taking the lock, so checking it under the lock means we
+        // can't miss a ring that happens before we start waiting.
+        if word.load(core::sync::atomic::Ordering::SeqCst) != expected {
+            return;
+        }
+        let start = *rings;
+        let _rings = self.cv.wait_while(rings, |r| *r == start).unwrap();
+    }
+
+    fn wake(&self, _word: &Counter) {
+        *self.rings.lock().unwrap() += 1;
+        self.cv.notify_all();
+    }
+}
+
+impl<T: EntryData> RawQueue<T> {
+    /// Construct a new raw queue out of a header reference and a buffer pointer, like
+    /// [RawQueue::new], bundled with a new [StdWaiter].
+    /// # Safety
+    /// See [RawQueue::new].
+    pub unsafe fn with_std_waiter(
+        hdr: *const RawQueueHdr,
+        buf: *mut QueueEntry<T>,
+    ) -> WaiterQueue<T, StdWaiter> {
+        Self::with_waiter(hdr, buf, StdWaiter::new())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{QueueEntry, RawQueue, RawQueueHdr, ReceiveFlags, SubmissionFlags};
+
+    #[test]
+    fn it_waits_with_std() {
+        // Don't spin, so that both sides end up waiting.
+        let qh = RawQueueHdr::with_spin_limit(2, std::mem::size_of::<QueueEntry<u32>>(), 0);
+        let mut buffer = [QueueEntry::<i32>::default(); 1 << 2];
+        let q = unsafe { RawQueue::with_std_waiter(&qh, buffer.as_mut_ptr()) };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    assert_eq!(
+                        q.submit(QueueEntry::new(i, 7), SubmissionFlags::empty()),
+                        Ok(())
+                    );
+                }
+            });
+            for i in 0..1000 {
+                assert_eq!(q.receive(ReceiveFlags::empty()).unwrap().info(), i);
+            }
+        });
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    #[test]
+    fn it_waits_with_non_copy_data() {
+        use crate::test_util::{Counted, Drops};
+
+        let qh = RawQueueHdr::new(2, std::mem::size_of::<QueueEntry<Counted>>());
+        let mut buffer: Vec<_> = (0..4).map(|_| std::mem::MaybeUninit::uninit()).collect();
+        let q = unsafe { RawQueue::with_std_waiter(&qh, RawQueue::init_buffer(&mut buffer)) };
+        let drops = Drops::default();
+        for i in 0..10 {
+            let res = q.submit(
+                QueueEntry::new(i, drops.payload()),
+                SubmissionFlags::empty(),
+            );
+            assert_eq!(res, Ok(()));
+            assert_eq!(q.receive(ReceiveFlags::empty()).unwrap().info(), i);
+            // Each payload is dropped once, by the receiver, and not again from its slot.
+            assert_eq!(drops.count(), i as usize + 1);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Counts the drops of the [Counted] payloads it hands out, for checking that a queue drops each
+/// entry exactly once.
+#[derive(Default)]
+pub(crate) struct Drops(Arc<AtomicUsize>);
+
+impl Drops {
+    /// A new payload whose drop is counted here.
+    pub(crate) fn payload(&self) -> Counted {
+        Counted(self.0.clone())
+    }
+
+    /// The number of payloads dropped so far.
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A payload that counts its drop in the [Drops] it came from.
+pub(crate) struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
@@ -80,8 +80,10 @@ impl<S: Copy, C: Copy> Queue<S, C> {
         const HDR_LEN: usize = 0x1000;
         let obj: Object<QueueBase<S, C>> = Object::create_with(create_spec, |obj| unsafe {
             // TODO: verify things
-            let sub_len = (core::mem::size_of::<S>() * sub_queue_len) * 2;
-            //let com_len = (core::mem::size_of::<C>() * com_queue_len) * 2;
+            let sub_l2len = sub_queue_len.next_power_of_two().ilog2() as usize;
+            let com_l2len = com_queue_len.next_power_of_two().ilog2() as usize;
+            let sub_len = core::mem::size_of::<QueueEntry<S>>() << sub_l2len;
+            //let com_len = core::mem::size_of::<QueueEntry<C>>() << com_l2len;
             let (sub_hdr, com_hdr) = {
                 let base: &mut QueueBase<S, C> = obj.base_mut_unchecked().assume_init_mut();
                 base.sub_hdr = NULLPAGE_SIZE + HDR_LEN;
@@ -92,10 +94,14 @@ impl<S: Copy, C: Copy> Queue<S, C> {
             };
             let srq: *mut RawQueueHdr = obj.raw_lea_mut(sub_hdr);
             let crq: *mut RawQueueHdr = obj.raw_lea_mut(com_hdr);
-            let l2len = sub_queue_len.next_power_of_two().ilog2();
-            srq.write(RawQueueHdr::new(l2len as usize, core::mem::size_of::<S>()));
-            let l2len = com_queue_len.next_power_of_two().ilog2();
-            crq.write(RawQueueHdr::new(l2len as usize, core::mem::size_of::<C>()));
+            srq.write(RawQueueHdr::new(
+                sub_l2len,
+                core::mem::size_of::<QueueEntry<S>>(),
+            ));
+            crq.write(RawQueueHdr::new(
+                com_l2len,
+                core::mem::size_of::<QueueEntry<C>>(),
+            ));
         })?;
         Ok(obj.into())
     }